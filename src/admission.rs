@@ -0,0 +1,189 @@
+//! Admission control for inbound peer connections. Connections are counted from accept until
+//! closed, and as pending until the peer proves its node id.
+
+use crate::codec::RejectReason;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+
+/// Rejections being sent at once, connections over this are dropped without a reason.
+const MAX_REJECTING: usize = 64;
+
+/// Connection counts, `None` for no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionLimits {
+    pub max_connections: Option<usize>,
+    pub max_per_ip: Option<usize>,
+    pub max_pending: Option<usize>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ConnectionStats {
+    pub limits: ConnectionLimits,
+    pub connections: usize,
+    pub pending: usize,
+    pub rejected: u64,
+    pub per_ip: HashMap<IpAddr, usize>,
+}
+
+#[derive(Default)]
+struct Admission {
+    limits: ConnectionLimits,
+    connections: usize,
+    pending: usize,
+    rejecting: usize,
+    rejected: u64,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+fn exceeds(limit: Option<usize>, count: usize) -> bool {
+    limit.is_some_and(|limit| count >= limit)
+}
+
+impl Admission {
+    fn admit(&mut self, ip: IpAddr) -> Result<(), RejectReason> {
+        let from_ip = self.per_ip.get(&ip).copied().unwrap_or_default();
+        let reason = if exceeds(self.limits.max_connections, self.connections) {
+            RejectReason::ServerFull
+        } else if exceeds(self.limits.max_pending, self.pending) {
+            RejectReason::PendingLimit
+        } else if exceeds(self.limits.max_per_ip, from_ip) {
+            RejectReason::AddressLimit
+        } else {
+            self.connections += 1;
+            self.pending += 1;
+            *self.per_ip.entry(ip).or_insert(0) += 1;
+            return Ok(());
+        };
+        self.rejected += 1;
+        Err(reason)
+    }
+
+    fn handshake_done(&mut self) {
+        self.pending -= 1;
+    }
+
+    fn release(&mut self, ip: IpAddr, pending: bool) {
+        self.connections -= 1;
+        if pending {
+            self.pending -= 1;
+        }
+        if let Some(count) = self.per_ip.get_mut(&ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.per_ip.remove(&ip);
+            }
+        }
+    }
+
+    fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            limits: self.limits,
+            connections: self.connections,
+            pending: self.pending,
+            rejected: self.rejected,
+            per_ip: self.per_ip.clone(),
+        }
+    }
+}
+
+fn admission() -> &'static Mutex<Admission> {
+    static ADMISSION: OnceLock<Mutex<Admission>> = OnceLock::new();
+    ADMISSION.get_or_init(Default::default)
+}
+
+/// Slot of an admitted connection, released on drop.
+pub struct Ticket {
+    ip: IpAddr,
+    pending: bool,
+}
+
+impl Ticket {
+    /// Peer proved its node id, the connection no longer counts as pending.
+    pub fn handshake_done(&mut self) {
+        if self.pending {
+            self.pending = false;
+            admission().lock().unwrap().handshake_done();
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        admission().lock().unwrap().release(self.ip, self.pending)
+    }
+}
+
+/// Slot of a connection being sent a rejection, released on drop.
+pub struct Rejecting(());
+
+impl Drop for Rejecting {
+    fn drop(&mut self) {
+        admission().lock().unwrap().rejecting -= 1;
+    }
+}
+
+pub fn set_limits(limits: ConnectionLimits) {
+    log::info!("connection limits: {:?}", limits);
+    admission().lock().unwrap().limits = limits;
+}
+
+pub fn stats() -> ConnectionStats {
+    admission().lock().unwrap().stats()
+}
+
+/// Counts a new connection from `ip`, or tells why it has to be rejected.
+pub fn admit(ip: IpAddr) -> Result<Ticket, RejectReason> {
+    admission().lock().unwrap().admit(ip)?;
+    Ok(Ticket { ip, pending: true })
+}
+
+/// Slot for sending a rejection, `None` when too many are in progress already.
+pub fn start_reject() -> Option<Rejecting> {
+    let mut admission = admission().lock().unwrap();
+    if admission.rejecting >= MAX_REJECTING {
+        return None;
+    }
+    admission.rejecting += 1;
+    Some(Rejecting(()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_admission() {
+        let peer_a: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer_b: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut admission = Admission {
+            limits: ConnectionLimits {
+                max_connections: Some(3),
+                max_per_ip: Some(2),
+                max_pending: Some(2),
+            },
+            ..Admission::default()
+        };
+
+        assert_eq!(admission.admit(peer_a), Ok(()));
+        assert_eq!(admission.admit(peer_a), Ok(()));
+        assert_eq!(admission.admit(peer_b), Err(RejectReason::PendingLimit));
+        admission.handshake_done();
+        admission.handshake_done();
+        assert_eq!(admission.admit(peer_a), Err(RejectReason::AddressLimit));
+        assert_eq!(admission.admit(peer_b), Ok(()));
+        assert_eq!(admission.admit(peer_b), Err(RejectReason::ServerFull));
+
+        admission.release(peer_a, false);
+        admission.release(peer_b, true);
+        let stats = admission.stats();
+        assert_eq!(stats.connections, 1);
+        assert_eq!(stats.pending, 0);
+        assert_eq!(stats.rejected, 3);
+        assert_eq!(stats.per_ip.get(&peer_a), Some(&1));
+        assert_eq!(stats.per_ip.get(&peer_b), None);
+    }
+}
@@ -0,0 +1,102 @@
+//! Content-defined chunking, FastCDC style. Cut points depend only on the bytes around them, so an
+//! insertion changes the chunks it touches and leaves the rest of the file hashing the same.
+//! Below the average size a stricter mask is used and above it a looser one, which keeps chunk
+//! sizes close to the average.
+
+use crate::filemap::BLOCK_SIZE;
+
+pub const MIN_SIZE: usize = 256 * 1024;
+pub const AVG_SIZE: usize = 1024 * 1024;
+/// Chunks are served as blocks, so they may not be bigger.
+pub const MAX_SIZE: usize = BLOCK_SIZE;
+
+/// Gear hash bits that have to be zero for a cut, log2(AVG_SIZE) plus or minus 2.
+const MASK_SMALL: u64 = !0 << (64 - 22);
+const MASK_LARGE: u64 = !0 << (64 - 18);
+
+const GEAR: [u64; 256] = gear_table();
+
+/// Random values from splitmix64 with a fixed seed, chunk boundaries depend on them.
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// Length of the first chunk of `data`, which has to hold at least `MAX_SIZE` bytes unless it
+/// is the rest of the file.
+pub fn cut(data: &[u8]) -> usize {
+    if data.len() <= MIN_SIZE {
+        return data.len();
+    }
+    let end = data.len().min(MAX_SIZE);
+    let normal = end.min(AVG_SIZE);
+    let mut hash = 0u64;
+    let mut i = MIN_SIZE;
+    while i < end {
+        hash = (hash << 1).wrapping_add(GEAR[data[i] as usize]);
+        let mask = if i < normal { MASK_SMALL } else { MASK_LARGE };
+        if hash & mask == 0 {
+            return i + 1;
+        }
+        i += 1;
+    }
+    end
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state
+                    .wrapping_mul(6_364_136_223_846_793_005)
+                    .wrapping_add(1_442_695_040_888_963_407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    fn chunks(mut data: &[u8]) -> Vec<&[u8]> {
+        let mut chunks = Vec::new();
+        while !data.is_empty() {
+            let len = cut(data);
+            chunks.push(&data[..len]);
+            data = &data[len..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn test_cut() {
+        let data = data(16 * 1024 * 1024, 7);
+        let original = chunks(&data);
+        assert!(original.len() > 4);
+        for chunk in &original[..original.len() - 1] {
+            assert!(chunk.len() > MIN_SIZE && chunk.len() <= MAX_SIZE);
+        }
+
+        // Insertion near the start only changes the chunks around it.
+        let mut edited = data[..1000].to_vec();
+        edited.push(42);
+        edited.extend_from_slice(&data[1000..]);
+        let edited = chunks(&edited);
+        let shared = original.iter().filter(|c| edited.contains(c)).count();
+        assert!(shared >= original.len() - 2);
+
+        assert_eq!(cut(&data[..100]), 100);
+        assert_eq!(cut(&[]), 0);
+    }
+}
@@ -1,17 +1,24 @@
-use crate::filemap::FileMap;
+use crate::filemap::Manifest;
+use crate::identity::CHALLENGE_SIZE;
 use actix::Message;
 use bytes::{BufMut, ByteOrder, BytesMut, LittleEndian};
 
 use serde::{Deserialize, Serialize};
 use std::convert::{TryFrom, TryInto};
-use std::fmt::Display;
+use std::fmt::{self, Display};
 use std::io;
 use tokio_io::codec::{Decoder, Encoder};
 
-const PROTO_VERSION: u8 = 1;
+const PROTO_VERSION: u8 = 3;
 
 const MAX_PACKET_SIZE: usize = 1024 * 1024 * 8;
 
+/// Manifests bigger than this are sent in pages.
+pub const MAX_MANIFEST_PAGE_SIZE: u64 = 1024 * 1024 * 4;
+
+/// Most blocks asked for in a single `GetBlocks`.
+pub const MAX_BATCH_BLOCKS: usize = 256;
+
 pub fn hash_to_hex(hash: u128) -> String {
     format!("{:032x}", hash)
 }
@@ -25,6 +32,18 @@ pub enum Op {
     GetBlock = 4,
     Block = 5,
     Bye = 6,
+    Challenge = 7,
+    Auth = 8,
+    GetRange = 9,
+    Range = 10,
+    GetBlockHashes = 11,
+    BlockHashes = 12,
+    GetManifestPage = 13,
+    ManifestPage = 14,
+    Pause = 15,
+    Resume = 16,
+    Reject = 17,
+    GetBlocks = 18,
 }
 
 pub enum StCommand {
@@ -35,6 +54,20 @@ pub enum StCommand {
     GetBlock(GetBlock),
     Block(Block),
     Bye,
+    Challenge([u8; CHALLENGE_SIZE]),
+    Auth(Auth),
+    GetRange(GetRange),
+    Range(Range),
+    GetBlockHashes(GetBlockHashes),
+    BlockHashes(BlockHashes),
+    GetManifestPage(GetManifestPage),
+    ManifestPage(ManifestPage),
+    /// Sender stopped reading, no new requests until resume.
+    Pause,
+    Resume,
+    /// Connection refused by the server, sent instead of hello.
+    Reject(RejectReason),
+    GetBlocks(GetBlocks),
 }
 
 impl StCommand {
@@ -42,7 +75,7 @@ impl StCommand {
         StCommand::Hello(Hello::new(id))
     }
 
-    pub fn ask_reply(hash: u128, files: Option<Vec<FileMap>>) -> Self {
+    pub fn ask_reply(hash: u128, files: Option<Manifest>) -> Self {
         StCommand::AskReply(AskReply { hash, files })
     }
 
@@ -70,6 +103,43 @@ impl StCommand {
                 b.hash, b.file_nr, b.block_nr
             ),
             StCommand::Bye => format!("[bye]"),
+            StCommand::Challenge(_) => "[challenge]".to_string(),
+            StCommand::Auth(a) => format!("[auth key-len:{}]", a.public_key.len()),
+            StCommand::GetRange(r) => format!(
+                "[get-range hash:{}, file-no:{}, offset:{}, len:{}]",
+                r.hash, r.file_nr, r.offset, r.len
+            ),
+            StCommand::Range(r) => format!(
+                "[range hash:{}, file-no:{}, offset:{}, len:{}]",
+                r.hash, r.file_nr, r.offset, r.len
+            ),
+            StCommand::GetBlockHashes(r) => format!(
+                "[get-block-hashes hash:{}, file-no:{}, start:{}, count:{}]",
+                r.hash, r.file_nr, r.start, r.count
+            ),
+            StCommand::BlockHashes(r) => format!(
+                "[block-hashes hash:{}, file-no:{}, start:{}, count:{}]",
+                r.hash,
+                r.file_nr,
+                r.start,
+                r.hashes.len()
+            ),
+            StCommand::GetManifestPage(p) => format!(
+                "[get-manifest-page hash:{}, first-file:{}]",
+                p.hash, p.first_file
+            ),
+            StCommand::ManifestPage(p) => format!(
+                "[manifest-page hash:{}, first-file:{}, files:{}]",
+                p.hash,
+                p.first_file,
+                p.files.len()
+            ),
+            StCommand::Pause => "[pause]".to_string(),
+            StCommand::Resume => "[resume]".to_string(),
+            StCommand::Reject(reason) => format!("[reject {}]", reason),
+            StCommand::GetBlocks(b) => {
+                format!("[get-blocks hash:{}, blocks:{}]", b.hash, b.blocks.len())
+            }
         }
     }
 }
@@ -84,6 +154,18 @@ impl StCommand {
             Op::GetBlock => StCommand::GetBlock(bincode::deserialize(buf)?),
             Op::Block => StCommand::Block(bincode::deserialize(buf)?),
             Op::Bye => StCommand::Bye,
+            Op::Challenge => StCommand::Challenge(bincode::deserialize(buf)?),
+            Op::Auth => StCommand::Auth(bincode::deserialize(buf)?),
+            Op::GetRange => StCommand::GetRange(bincode::deserialize(buf)?),
+            Op::Range => StCommand::Range(bincode::deserialize(buf)?),
+            Op::GetBlockHashes => StCommand::GetBlockHashes(bincode::deserialize(buf)?),
+            Op::BlockHashes => StCommand::BlockHashes(bincode::deserialize(buf)?),
+            Op::GetManifestPage => StCommand::GetManifestPage(bincode::deserialize(buf)?),
+            Op::ManifestPage => StCommand::ManifestPage(bincode::deserialize(buf)?),
+            Op::Pause => StCommand::Pause,
+            Op::Resume => StCommand::Resume,
+            Op::Reject => StCommand::Reject(bincode::deserialize(buf)?),
+            Op::GetBlocks => StCommand::GetBlocks(bincode::deserialize(buf)?),
         })
     }
 }
@@ -98,6 +180,18 @@ impl Op {
             Op::GetBlock => None,
             Op::Block => None,
             Op::Bye => Some(0),
+            Op::Challenge => Some(CHALLENGE_SIZE as u32),
+            Op::Auth => None,
+            Op::GetRange => None,
+            Op::Range => None,
+            Op::GetBlockHashes => None,
+            Op::BlockHashes => None,
+            Op::GetManifestPage => Some(20),
+            Op::ManifestPage => None,
+            Op::Pause => Some(0),
+            Op::Resume => Some(0),
+            Op::Reject => Some(4),
+            Op::GetBlocks => None,
         }
     }
 }
@@ -114,6 +208,18 @@ impl TryFrom<u8> for Op {
             4 => Ok(Op::GetBlock),
             5 => Ok(Op::Block),
             6 => Ok(Op::Bye),
+            7 => Ok(Op::Challenge),
+            8 => Ok(Op::Auth),
+            9 => Ok(Op::GetRange),
+            10 => Ok(Op::Range),
+            11 => Ok(Op::GetBlockHashes),
+            12 => Ok(Op::BlockHashes),
+            13 => Ok(Op::GetManifestPage),
+            14 => Ok(Op::ManifestPage),
+            15 => Ok(Op::Pause),
+            16 => Ok(Op::Resume),
+            17 => Ok(Op::Reject),
+            18 => Ok(Op::GetBlocks),
             _ => Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "unknown packet opcode",
@@ -141,8 +247,9 @@ impl Hello {
     }
 }
 
+/// Resolves to the node id proven by the peer.
 impl Message for Hello {
-    type Result = Result<(), super::error::Error>;
+    type Result = Result<u128, super::error::Error>;
 }
 
 pub struct Bye {}
@@ -157,6 +264,25 @@ impl Message for Bye {
     type Result = Result<(), super::error::Error>;
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    ServerFull,
+    AddressLimit,
+    PendingLimit,
+    Banned,
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            RejectReason::ServerFull => "too many connections",
+            RejectReason::AddressLimit => "too many connections from address",
+            RejectReason::PendingLimit => "too many pending handshakes",
+            RejectReason::Banned => "banned",
+        })
+    }
+}
+
 #[derive(Default, Serialize, Deserialize)]
 pub struct Ask {
     pub hash: u128,
@@ -173,11 +299,18 @@ impl Message for Ask {
     type Result = Result<AskReply, crate::error::Error>;
 }
 
-#[derive(Default, Serialize, Deserialize)]
+#[derive(Default, Serialize, Deserialize, Clone)]
 pub struct AskReply {
     pub hash: u128,
     // None if unknown hash
-    pub files: Option<Vec<FileMap>>,
+    pub files: Option<Manifest>,
+}
+
+/// Proof of node id ownership, signature of the challenge received from the peer.
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct Auth {
+    pub public_key: Vec<u8>,
+    pub signature: Vec<u8>,
 }
 
 #[derive(Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
@@ -191,6 +324,18 @@ impl Message for GetBlock {
     type Result = Result<Block, crate::error::Error>;
 }
 
+/// Request for many blocks at once, answered with a `Block` packet for each pair of
+/// (file_nr, block_nr). At most `MAX_BATCH_BLOCKS` pairs.
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct GetBlocks {
+    pub hash: u128,
+    pub blocks: Vec<(u32, u32)>,
+}
+
+impl Message for GetBlocks {
+    type Result = Result<Vec<Block>, crate::error::Error>;
+}
+
 #[derive(Default, Serialize, Deserialize, Clone)]
 pub struct Block {
     pub hash: u128,
@@ -199,6 +344,103 @@ pub struct Block {
     pub bytes: Vec<u8>,
 }
 
+/// Request for `len` bytes of file starting at `offset`. Range must fit in a single block.
+#[derive(Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
+pub struct GetRange {
+    pub hash: u128,
+    pub file_nr: u32,
+    pub offset: u64,
+    pub len: u32,
+}
+
+impl Message for GetRange {
+    type Result = Result<Range, crate::error::Error>;
+}
+
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct Range {
+    pub hash: u128,
+    pub file_nr: u32,
+    pub offset: u64,
+    pub len: u32,
+    /// Block chunks covering requested range.
+    pub chunks: Vec<u8>,
+    /// Merkle proof of the chunks against block hash.
+    pub proof: Vec<u128>,
+}
+
+impl Range {
+    pub fn request(&self) -> GetRange {
+        GetRange {
+            hash: self.hash,
+            file_nr: self.file_nr,
+            offset: self.offset,
+            len: self.len,
+        }
+    }
+}
+
+/// Request for `count` block hashes of a tree format file, starting at block `start`.
+#[derive(Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
+pub struct GetBlockHashes {
+    pub hash: u128,
+    pub file_nr: u32,
+    pub start: u32,
+    pub count: u32,
+}
+
+impl Message for GetBlockHashes {
+    type Result = Result<BlockHashes, crate::error::Error>;
+}
+
+#[derive(Default, Serialize, Deserialize, Clone)]
+pub struct BlockHashes {
+    pub hash: u128,
+    pub file_nr: u32,
+    pub start: u32,
+    pub hashes: Vec<u128>,
+    /// Merkle proof of the hashes against file root.
+    pub proof: Vec<u128>,
+}
+
+impl BlockHashes {
+    pub fn request(&self) -> GetBlockHashes {
+        GetBlockHashes {
+            hash: self.hash,
+            file_nr: self.file_nr,
+            start: self.start,
+            count: self.hashes.len() as u32,
+        }
+    }
+}
+
+/// Request for manifest entries starting at `first_file`, sender decides how many fit.
+#[derive(Default, Serialize, Deserialize, Hash, PartialEq, Eq, Clone)]
+pub struct GetManifestPage {
+    pub hash: u128,
+    pub first_file: u32,
+}
+
+impl Message for GetManifestPage {
+    type Result = Result<ManifestPage, crate::error::Error>;
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ManifestPage {
+    pub hash: u128,
+    pub first_file: u32,
+    pub files: Manifest,
+}
+
+impl ManifestPage {
+    pub fn request(&self) -> GetManifestPage {
+        GetManifestPage {
+            hash: self.hash,
+            first_file: self.first_file,
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct StCodec {}
 
@@ -260,8 +502,43 @@ impl Encoder for StCodec {
         let (op, prefix_size, size) = match &msg {
             StCommand::Nop => (Op::Nop, 0usize, 0usize),
             StCommand::Bye => (Op::Bye, 0usize, 0usize),
+            StCommand::Pause => (Op::Pause, 0, 0),
+            StCommand::Resume => (Op::Resume, 0, 0),
+            StCommand::Reject(..) => (Op::Reject, 0, 4),
             StCommand::Hello(..) => (Op::Hello, 0, 17),
             StCommand::Ask(..) => (Op::Ask, 0, 16),
+            StCommand::Challenge(..) => (Op::Challenge, 0, CHALLENGE_SIZE),
+            StCommand::Auth(auth) => (
+                Op::Auth,
+                4,
+                bincode::serialized_size(auth).unwrap() as usize,
+            ),
+            StCommand::GetRange(get_range) => (
+                Op::GetRange,
+                4,
+                bincode::serialized_size(get_range).unwrap() as usize,
+            ),
+            StCommand::Range(range) => (
+                Op::Range,
+                4,
+                bincode::serialized_size(range).unwrap() as usize,
+            ),
+            StCommand::GetBlockHashes(get_hashes) => (
+                Op::GetBlockHashes,
+                4,
+                bincode::serialized_size(get_hashes).unwrap() as usize,
+            ),
+            StCommand::BlockHashes(hashes) => (
+                Op::BlockHashes,
+                4,
+                bincode::serialized_size(hashes).unwrap() as usize,
+            ),
+            StCommand::GetManifestPage(..) => (Op::GetManifestPage, 0, 20),
+            StCommand::ManifestPage(page) => (
+                Op::ManifestPage,
+                4,
+                bincode::serialized_size(page).unwrap() as usize,
+            ),
             StCommand::AskReply(reply) => (
                 Op::AskReply,
                 4,
@@ -277,6 +554,11 @@ impl Encoder for StCodec {
                 4,
                 bincode::serialized_size(block).unwrap() as usize,
             ),
+            StCommand::GetBlocks(get_blocks) => (
+                Op::GetBlocks,
+                4,
+                bincode::serialized_size(get_blocks).unwrap() as usize,
+            ),
         };
         dst.reserve(1 + prefix_size + size);
 
@@ -289,11 +571,23 @@ impl Encoder for StCodec {
         match msg {
             StCommand::Nop => Ok(()),
             StCommand::Bye => Ok(()),
+            StCommand::Pause => Ok(()),
+            StCommand::Resume => Ok(()),
+            StCommand::Reject(reason) => put_into_buf(size, dst, &reason),
             StCommand::Hello(hello) => put_into_buf(size, dst, &hello),
             StCommand::Ask(ask) => put_into_buf(size, dst, &ask),
             StCommand::AskReply(ask_reply) => put_into_buf(size, dst, &ask_reply),
             StCommand::GetBlock(get_block) => put_into_buf(size, dst, &get_block),
             StCommand::Block(block) => put_into_buf(size, dst, &block),
+            StCommand::GetBlocks(get_blocks) => put_into_buf(size, dst, &get_blocks),
+            StCommand::Challenge(challenge) => put_into_buf(size, dst, &challenge),
+            StCommand::Auth(auth) => put_into_buf(size, dst, &auth),
+            StCommand::GetRange(get_range) => put_into_buf(size, dst, &get_range),
+            StCommand::Range(range) => put_into_buf(size, dst, &range),
+            StCommand::GetBlockHashes(get_hashes) => put_into_buf(size, dst, &get_hashes),
+            StCommand::BlockHashes(hashes) => put_into_buf(size, dst, &hashes),
+            StCommand::GetManifestPage(get_page) => put_into_buf(size, dst, &get_page),
+            StCommand::ManifestPage(page) => put_into_buf(size, dst, &page),
         }
     }
 }
@@ -312,6 +606,28 @@ mod test {
         let ask_size = bincode::serialized_size(&Ask::default()).unwrap() as u32;
 
         assert_eq!(ask_size, 16);
+
+        let page_size = bincode::serialized_size(&GetManifestPage::default()).unwrap() as u32;
+
+        assert_eq!(Op::GetManifestPage.size(), Some(page_size));
+
+        let reject_size = bincode::serialized_size(&RejectReason::PendingLimit).unwrap() as u32;
+
+        assert_eq!(Op::Reject.size(), Some(reject_size));
+    }
+
+    /// Fails when a packet is added without a protocol version bump, see the version list in
+    /// PROTOCOL.md.
+    #[test]
+    fn test_proto_version() {
+        assert_eq!(PROTO_VERSION, 3);
+        assert!(Op::try_from(Op::GetBlocks as u8).is_ok());
+        assert!(Op::try_from(Op::GetBlocks as u8 + 1).is_err());
+        assert!(!Hello {
+            proto_version: PROTO_VERSION - 1,
+            node_id: 0,
+        }
+        .is_valid());
     }
 
     #[test]
@@ -344,6 +660,125 @@ mod test {
         }
     }
 
+    #[test]
+    fn test_auth() {
+        let mut codec = StCodec::default();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(StCommand::Challenge([7; CHALLENGE_SIZE]), &mut buf)
+            .unwrap();
+        codec
+            .encode(
+                StCommand::Auth(Auth {
+                    public_key: vec![1; 32],
+                    signature: vec![2; 64],
+                }),
+                &mut buf,
+            )
+            .unwrap();
+
+        let mut r = buf.take();
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::Challenge(challenge) => assert_eq!(challenge, [7; CHALLENGE_SIZE]),
+            _ => panic!("unexpected packet"),
+        }
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::Auth(auth) => {
+                assert_eq!(auth.public_key, vec![1; 32]);
+                assert_eq!(auth.signature, vec![2; 64]);
+            }
+            _ => panic!("unexpected packet"),
+        }
+        assert!(r.is_empty());
+    }
+
+    #[test]
+    fn test_range() {
+        let mut codec = StCodec::default();
+        let range = Range {
+            hash: 0x1212deadbeef1212,
+            file_nr: 2,
+            offset: 5 << 32,
+            len: 3,
+            chunks: vec![1, 2, 3, 4],
+            proof: vec![7, 8],
+        };
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(StCommand::GetRange(range.request()), &mut buf)
+            .unwrap();
+        codec
+            .encode(StCommand::Range(range.clone()), &mut buf)
+            .unwrap();
+
+        let mut r = buf.take();
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::GetRange(get_range) => assert!(get_range == range.request()),
+            _ => panic!("unexpected packet"),
+        }
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::Range(decoded) => {
+                assert!(decoded.request() == range.request());
+                assert_eq!(decoded.chunks, range.chunks);
+                assert_eq!(decoded.proof, range.proof);
+            }
+            _ => panic!("unexpected packet"),
+        }
+    }
+
+    #[test]
+    fn test_block_hashes() {
+        let mut codec = StCodec::default();
+        let hashes = BlockHashes {
+            hash: 0x1212deadbeef1212,
+            file_nr: 1,
+            start: 7,
+            hashes: vec![1, 2, 3],
+            proof: vec![4],
+        };
+
+        let mut buf = BytesMut::new();
+        codec
+            .encode(StCommand::GetBlockHashes(hashes.request()), &mut buf)
+            .unwrap();
+        codec
+            .encode(
+                StCommand::AskReply(AskReply {
+                    hash: hashes.hash,
+                    files: Some(Manifest::Tree(Vec::new())),
+                }),
+                &mut buf,
+            )
+            .unwrap();
+        codec
+            .encode(StCommand::BlockHashes(hashes.clone()), &mut buf)
+            .unwrap();
+
+        let mut r = buf.take();
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::GetBlockHashes(get_hashes) => {
+                assert!(get_hashes == hashes.request());
+                assert_eq!(get_hashes.count, 3);
+            }
+            _ => panic!("unexpected packet"),
+        }
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::AskReply(AskReply {
+                files: Some(Manifest::Tree(headers)),
+                ..
+            }) => assert!(headers.is_empty()),
+            _ => panic!("unexpected packet"),
+        }
+        match codec.decode(&mut r).unwrap().unwrap() {
+            StCommand::BlockHashes(decoded) => {
+                assert!(decoded.request() == hashes.request());
+                assert_eq!(decoded.proof, hashes.proof);
+            }
+            _ => panic!("unexpected packet"),
+        }
+    }
+
     #[test]
     fn test_block() {
         let mut codec = StCodec::default();
@@ -375,5 +810,4 @@ mod test {
             _ => assert!(false),
         }
     }
-
 }
@@ -1,3 +1,5 @@
+use crate::filemap::MapFormat;
+use crate::ratelimit::Limits;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
@@ -13,6 +15,18 @@ pub enum Command {
         timeout: Option<f64>,
         hash: Option<String>,
         #[serde(default)]
+        format: MapFormat,
+        /// Globs selecting files of uploaded directories, all files when empty.
+        #[serde(default)]
+        include: Vec<String>,
+        /// Globs of files and directories left out of uploaded directories.
+        #[serde(default)]
+        exclude: Vec<String>,
+        /// Share file modes, modification times and symlinks, defaults to the `--metadata`
+        /// option.
+        #[serde(default)]
+        metadata: Option<bool>,
+        #[serde(default)]
         user: Option<User>,
     },
     Download {
@@ -20,6 +34,49 @@ pub enum Command {
         dest: PathBuf,
         peers: Vec<PeerInfo>,
         timeout: Option<f64>,
+        /// Download rate in bytes per second.
+        #[serde(default)]
+        limit: Option<u64>,
+        /// Share downloaded files, defaults to the `--seed` option.
+        #[serde(default)]
+        seed: Option<bool>,
+        /// How long downloaded files are shared, in seconds.
+        #[serde(default)]
+        seed_lifetime: Option<u64>,
+        /// Older version of the resource to reuse blocks from.
+        #[serde(default)]
+        basis: Option<Basis>,
+        /// Restore file metadata, defaults to the `--metadata` option.
+        #[serde(default)]
+        metadata: Option<bool>,
+        /// Names or glob patterns of files to download, all files when empty.
+        #[serde(default)]
+        files: Vec<String>,
+        #[serde(default)]
+        user: Option<User>,
+    },
+    DownloadRange {
+        hash: String,
+        #[serde(flatten)]
+        range: FileRange,
+        dest: PathBuf,
+        peers: Vec<PeerInfo>,
+        timeout: Option<f64>,
+        #[serde(default)]
+        user: Option<User>,
+    },
+    /// Current transfer rate limits, replaced first with `set` when given.
+    Limits {
+        #[serde(default)]
+        set: Option<Limits>,
+    },
+    /// Asks all peers for a resource and lists its files with the peers that have it, without
+    /// downloading them.
+    #[serde(alias = "manifest")]
+    Inspect {
+        hash: String,
+        peers: Vec<PeerInfo>,
+        timeout: Option<f64>,
         #[serde(default)]
         user: Option<User>,
     },
@@ -30,16 +87,25 @@ impl Command {
         match self {
             Command::Id => log::info!("command st ID"),
             Command::Addresses => log::info!("command st ADDRESSES"),
+            Command::Limits { set } => log::info!("command LIMITS set={:?}", set),
             Command::Upload {
                 files,
                 timeout,
                 hash,
+                format,
+                include,
+                exclude,
+                metadata,
                 user,
             } => log::info!(
-                "command UPLOAD files={:?} timeout={:?} hash={:?} user={:?}",
+                "command UPLOAD files={:?} timeout={:?} hash={:?} format={:?} include={:?} exclude={:?} metadata={:?} user={:?}",
                 files,
                 timeout,
                 hash,
+                format,
+                include,
+                exclude,
+                metadata,
                 user
             ),
             Command::Download {
@@ -47,15 +113,54 @@ impl Command {
                 dest,
                 peers,
                 timeout,
+                limit,
+                seed,
+                basis,
+                files,
+                user,
+                ..
+            } => log::info!(
+                "command DOWNLOAD hash={}, dest={} peers={:?} timeout={:?} limit={:?} seed={:?} basis={:?} files={:?} user={:?}",
+                hash,
+                dest.display(),
+                peers,
+                timeout,
+                limit,
+                seed,
+                basis,
+                files,
+                user
+            ),
+            Command::DownloadRange {
+                hash,
+                range,
+                dest,
+                peers,
+                timeout,
                 user,
             } => log::info!(
-                "command DOWNLOAD hash={}, dest={} peers={:?} timeout={:?} user={:?}",
+                "command DOWNLOADRANGE hash={}, file={}, offset={}, len={}, dest={} peers={:?} timeout={:?} user={:?}",
                 hash,
+                range.file,
+                range.offset,
+                range.len,
                 dest.display(),
                 peers,
                 timeout,
                 user
             ),
+            Command::Inspect {
+                hash,
+                peers,
+                timeout,
+                user,
+            } => log::info!(
+                "command INSPECT hash={}, peers={:?} timeout={:?} user={:?}",
+                hash,
+                peers,
+                timeout,
+                user
+            ),
         }
     }
 }
@@ -114,9 +219,56 @@ pub struct UploadResult {
     pub hash: String,
 }
 
+/// Local data a download can take blocks from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
+pub enum Basis {
+    /// Directory with files of an older version, searched recursively.
+    Dir(PathBuf),
+    /// Locally shared resource.
+    Hash(String),
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct DownloadResult {
     pub files: Vec<PathBuf>,
+    /// Bytes taken from local files instead of peers.
+    #[serde(default)]
+    pub bytes_saved: u64,
+    /// Requested names and patterns that matched any file, absent when all files were asked for.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub matched: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileInfo {
+    pub name: String,
+    pub size: u64,
+    pub blocks: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct InspectResult {
+    pub hash: String,
+    pub files: Vec<FileInfo>,
+    pub total_size: u64,
+    /// Peers that sent a manifest matching the hash.
+    pub peers: Vec<String>,
+}
+
+/// Byte range of a single file in a resource.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileRange {
+    pub file: String,
+    pub offset: u64,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DownloadRangeResult {
+    pub file: PathBuf,
+    pub offset: u64,
+    pub len: u64,
 }
 
 #[cfg(test)]
@@ -132,9 +284,37 @@ mod test {
         let upload_json = r#"{"command": "upload", "id": null, "files": {"/home/prekucki/.local/share/golem/default/rinkeby/ComputerRes/e339a264-71a9-11e9-b4e5-b6178fcd50f4/resources/e339a264-71a9-11e9-b4e5-b6178fcd50f4": "e339a264-71a9-11e9-b4e5-b6178fcd50f4"}, "timeout": null}"#;
         let upload_cmd: Command = serde_json::from_str(upload_json).unwrap();
         eprintln!("upload_cmd={:?}", upload_cmd);
+        let tree_json = r#"{"command": "upload", "files": {"/tmp/big.bin": "big.bin"}, "timeout": null, "format": "tree"}"#;
+        match serde_json::from_str(tree_json).unwrap() {
+            Command::Upload { format, .. } => assert_eq!(format, MapFormat::Tree),
+            _ => panic!("unexpected command"),
+        }
         let download_json = r#"{"command": "download", "hash": "c0ceff522b00eccb95c43b43af67c9585c3d914642339f770800dd164d8b42cc", "dest": "/home/prekucki/.local/share/golem/default/rinkeby/ComputerRes/nonce/tmp", "peers": [{"TCP": ["10.30.10.219", 3282]}, {"TCP": ["10.30.10.219", 3282]}, {"TCP": ["5.226.70.53", 3282]}, {"TCP": ["172.17.0.1", 3282]}], "size": null, "timeout": null}"#;
         let download_cmd: Command = serde_json::from_str(download_json).unwrap();
         eprintln!("upload_cmd={:?}", download_cmd);
+        let range_json = r#"{"command": "downloadrange", "hash": "c0ceff522b00eccb95c43b43af67c958", "file": "result.bin", "offset": 1024, "len": 4096, "dest": "/tmp/result.part", "peers": [{"TCP": ["10.30.10.219", 3282]}], "timeout": null}"#;
+        let range_cmd: Command = serde_json::from_str(range_json).unwrap();
+        eprintln!("range_cmd={:?}", range_cmd);
+        let select_json = r#"{"command": "download", "hash": "c0ceff522b00eccb95c43b43af67c958", "dest": "/tmp/out", "peers": [{"TCP": ["10.30.10.219", 3282]}], "timeout": null, "files": ["result.bin", "logs/*.txt"]}"#;
+        match serde_json::from_str(select_json).unwrap() {
+            Command::Download { files, .. } => assert_eq!(files, vec!["result.bin", "logs/*.txt"]),
+            _ => panic!("unexpected command"),
+        }
+        let manifest_json = r#"{"command": "manifest", "hash": "c0ceff522b00eccb95c43b43af67c958", "peers": [{"TCP": ["10.30.10.219", 3282]}], "timeout": null}"#;
+        match serde_json::from_str(manifest_json).unwrap() {
+            Command::Inspect { peers, .. } => assert_eq!(peers.len(), 1),
+            _ => panic!("unexpected command"),
+        }
+        let inspect_json = r#"{"command": "inspect", "hash": "c0ceff522b00eccb95c43b43af67c958", "peers": [{"TCP": ["10.30.10.219", 3282]}, {"TCP": ["5.226.70.53", 3282]}], "timeout": null}"#;
+        let inspect_cmd: Command = serde_json::from_str(inspect_json).unwrap();
+        match serde_json::from_str(r#"{"command": "limits", "set": {"upload": 1024}}"#).unwrap() {
+            Command::Limits { set: Some(limits) } => assert_eq!(limits.upload, Some(1024)),
+            _ => panic!("unexpected command"),
+        }
+        match serde_json::from_str(r#"{"command": "limits"}"#).unwrap() {
+            Command::Limits { set } => assert!(set.is_none()),
+            _ => panic!("unexpected command"),
+        }
+        eprintln!("inspect_cmd={:?}", inspect_cmd);
     }
-
 }
@@ -1,28 +1,37 @@
-use crate::codec::{AskReply, Block, GetBlock, StCodec, StCommand};
+use crate::admission::Ticket;
+use crate::codec::{
+    AskReply, Auth, Block, BlockHashes, GetBlock, GetBlockHashes, GetBlocks, GetManifestPage,
+    GetRange, ManifestPage, Range, RejectReason, StCodec, StCommand, MAX_BATCH_BLOCKS,
+    MAX_MANIFEST_PAGE_SIZE,
+};
 
 use crate::database;
 use crate::database::{DatabaseManager, FileDesc};
 use crate::error::{Error, ProtocolError};
-use crate::filemap::{FileMap, BLOCK_SIZE};
+use crate::filemap::{FileMap, Manifest, RangeSpan};
+use crate::flow::{Meter, MeteredCodec, MeteredWrite, MAX_OUTSTANDING_REQUESTS};
+use crate::identity::{self, Identity, CHALLENGE_SIZE};
+use crate::keepalive::{self, Liveness, Verdict};
+use crate::ratelimit;
+use crate::reader;
+use crate::reputation;
+use crate::transport::Transport;
 use actix::io::WriteHandler;
 use actix::prelude::*;
 use actix::{Actor, Addr, Context};
 
 use futures::unsync::oneshot;
-use std::cmp::min;
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
 use std::ops::Deref;
 use std::path::Path;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use std::{io, net};
 use tokio_codec::FramedRead;
 use tokio_io::io::WriteHalf;
 use tokio_io::AsyncRead;
-use tokio_tcp::TcpStream;
 
 static CONNECTION_IDS: AtomicUsize = AtomicUsize::new(0);
 
@@ -32,12 +41,32 @@ pub struct Connection {
     connection_id: usize,
     db: Addr<DatabaseManager>,
     peer_addr: net::SocketAddr,
-    framed: actix::io::FramedWrite<WriteHalf<TcpStream>, StCodec>,
+    framed: actix::io::FramedWrite<MeteredWrite<WriteHalf<Transport>>, MeteredCodec<StCodec>>,
+    meter: Meter,
+    /// Peer asked us to hold new requests.
+    peer_paused: bool,
+    deferred: VecDeque<StCommand>,
+    /// Blocks of batches waiting for a free request slot.
+    batched: VecDeque<GetBlock>,
+    identity: Arc<Identity>,
+    challenge: [u8; CHALLENGE_SIZE],
+    /// Node id from peer hello, not yet proven.
+    peer_claim: Option<u128>,
+    /// Node id proven by peer signature.
     peer_id: Option<u128>,
+    auth_requests: Vec<oneshot::Sender<Result<u128, Error>>>,
+    /// Noise handshake hash signed in auth, so signatures can't be relayed to another session.
+    handshake_hash: Vec<u8>,
     current_file: Option<Arc<database::FileDesc>>,
-    block_requests: HashMap<GetBlock, oneshot::Sender<Result<Block, Error>>>,
-    ask_requests: HashMap<u128, oneshot::Sender<Result<AskReply, Error>>>,
+    block_requests: Pending<GetBlock, Block>,
+    range_requests: Pending<GetRange, Range>,
+    hash_requests: Pending<GetBlockHashes, BlockHashes>,
+    page_requests: Pending<GetManifestPage, ManifestPage>,
+    ask_requests: Pending<u128, AskReply>,
     reporter: crate::user_report::UserReportHandle,
+    /// Admission slot of an inbound connection.
+    ticket: Option<Ticket>,
+    liveness: Liveness,
 }
 
 impl Drop for Connection {
@@ -69,6 +98,9 @@ impl Actor for Connection {
                 act.close_with_error(ProtocolError::HandshakeTimeout, ctx)
             }
         });
+        if let Some(tick) = self.liveness.config().tick() {
+            ctx.run_interval(tick, |act, ctx| act.check_liveness(ctx));
+        }
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
@@ -83,80 +115,118 @@ impl Actor for Connection {
 impl Connection {
     fn new_addr(
         db: Addr<DatabaseManager>,
-        tcp_stream: TcpStream,
+        transport: Transport,
         peer_addr: net::SocketAddr,
+        identity: Arc<Identity>,
+        ticket: Option<Ticket>,
         reporter: &crate::user_report::UserReportHandle,
     ) -> Addr<Connection> {
         let connection_id = CONNECTION_IDS.fetch_add(1, Ordering::SeqCst);
         let reporter = reporter.new_context();
         let addr: Addr<Connection> = Connection::create(move |ctx| {
-            let (r, w) = tcp_stream.split();
-            let framed = actix::io::FramedWrite::new(w, StCodec::default(), ctx);
-            log::debug!("opened connection id={}, peer={}", connection_id, peer_addr);
+            let encrypted = transport.is_encrypted();
+            let handshake_hash = transport.handshake_hash();
+            let (r, w) = transport.split();
+            let meter = Meter::new();
+            let framed =
+                actix::io::FramedWrite::new(meter.writer(w), meter.codec(StCodec::default()), ctx);
+            log::debug!(
+                "opened connection id={}, peer={}, encrypted={}",
+                connection_id,
+                peer_addr,
+                encrypted
+            );
 
             reporter.annotate("connection_id", &connection_id);
             reporter.annotate("peer", &peer_addr);
+            reporter.annotate("encrypted", &encrypted);
 
-            Connection::add_stream(FramedRead::new(r, StCodec::default()), ctx);
+            Connection::add_stream(meter.gate(FramedRead::new(r, StCodec::default())), ctx);
+            Connection::add_stream(meter.resumed(), ctx);
             Connection {
                 connection_id,
                 db,
                 framed,
+                meter,
+                peer_paused: false,
+                deferred: VecDeque::new(),
+                batched: VecDeque::new(),
                 peer_addr,
+                identity,
+                challenge: identity::new_challenge(),
+                peer_claim: None,
                 peer_id: None,
+                auth_requests: Vec::new(),
+                handshake_hash,
                 current_file: None,
-                block_requests: HashMap::new(),
-                ask_requests: HashMap::new(),
+                block_requests: Pending::default(),
+                range_requests: Pending::default(),
+                hash_requests: Pending::default(),
+                page_requests: Pending::default(),
+                ask_requests: Pending::default(),
                 reporter,
+                ticket,
+                liveness: Liveness::new(keepalive::config(), Instant::now()),
             }
         });
 
         addr
     }
 
-    pub fn new(
+    /// Starts inbound connection holding its admission slot.
+    pub fn accept(
         db: Addr<DatabaseManager>,
-        tcp_stream: TcpStream,
+        transport: Transport,
         peer_addr: net::SocketAddr,
-        reporter: &crate::user_report::UserReportHandle,
+        ticket: Ticket,
     ) -> impl Future<Item = Addr<Connection>, Error = Error> {
-        let id_fut = database::id(&db);
-        let addr = Self::new_addr(db, tcp_stream, peer_addr, reporter);
+        Self::start(
+            db,
+            transport,
+            peer_addr,
+            Some(ticket),
+            &crate::user_report::UserReportHandle::empty(),
+        )
+        .map(|(addr, _)| addr)
+    }
 
-        id_fut.and_then(move |id| {
-            addr.send(crate::codec::Hello::new(id))
+    fn start(
+        db: Addr<DatabaseManager>,
+        transport: Transport,
+        peer_addr: net::SocketAddr,
+        ticket: Option<Ticket>,
+        reporter: &crate::user_report::UserReportHandle,
+    ) -> impl Future<Item = (Addr<Connection>, u128), Error = Error> {
+        let reporter = reporter.clone();
+
+        database::identity(&db).and_then(move |identity| {
+            let node_id = identity.node_id();
+            let addr = Self::new_addr(db, transport, peer_addr, identity, ticket, &reporter);
+            addr.send(crate::codec::Hello::new(node_id))
                 .flatten()
-                .and_then(move |()| Ok(addr))
+                .and_then(move |peer_id| Ok((addr, peer_id)))
         })
     }
 
     pub fn new_managed(
         db: Addr<DatabaseManager>,
-        tcp_stream: TcpStream,
+        transport: Transport,
         peer_addr: net::SocketAddr,
         reporter: &crate::user_report::UserReportHandle,
     ) -> impl Future<Item = ConnectionRef, Error = Error> {
-        let id_fut = database::id(&db);
-        let addr = ConnectionRef(Self::new_addr(db, tcp_stream, peer_addr, reporter));
-
-        id_fut.and_then(move |id| {
-            addr.send(crate::codec::Hello::new(id))
-                .flatten()
-                .and_then(move |()| Ok(addr))
-        })
+        Self::start(db, transport, peer_addr, None, reporter)
+            .map(|(addr, peer_id)| ConnectionRef { addr, peer_id })
     }
 
     fn send_ask_reply(&mut self, file_desc: FileDesc, _ctx: &mut <Self as Actor>::Context) {
-        let reply = StCommand::ask_reply(
-            file_desc.map_hash,
-            Some(
-                file_desc
-                    .files
-                    .into_iter()
-                    .map(|(file_map, _path)| file_map)
-                    .collect(),
-            ),
-        );
+        let mut manifest = file_desc.manifest();
+        if bincode::serialized_size(&manifest).unwrap() > MAX_MANIFEST_PAGE_SIZE {
+            manifest = Manifest::Paged(manifest.header());
+        } else {
+            let maps = file_desc.files.iter().map(|(file_map, _)| file_map);
+            manifest = manifest.with_inline(maps, MAX_MANIFEST_PAGE_SIZE);
+        }
+        let reply = StCommand::ask_reply(file_desc.map_hash, Some(manifest));
 
         self.framed.write(reply)
     }
@@ -198,11 +268,17 @@ impl Connection {
                     fut::ok(())
                 }
             })
+            .then(|r, act: &mut Self, ctx| {
+                act.meter.request_done();
+                act.serve_batched(ctx);
+                fut::result(r)
+            })
             .map_err(|_e, act, ctx| {
                 log::error!("fail to handle ask from: {}", &act.peer_addr);
                 ctx.stop()
             });
 
+        self.meter.request_started();
         ctx.spawn(f);
     }
 
@@ -222,43 +298,293 @@ impl Connection {
             }
         };
 
-        if file_map.inline_data.len() > 0 && get_block.file_nr == 0 && get_block.block_nr == 0 {
-            self.framed.write(StCommand::block(
+        let inline = file_map
+            .files
+            .get(get_block.file_nr as usize)
+            .and_then(|(map, _)| map.inline.as_ref())
+            .filter(|_| get_block.block_nr == 0);
+        if let Some(data) = inline {
+            let command = StCommand::block(
                 get_block.hash,
                 get_block.file_nr,
                 get_block.block_nr,
-                file_map.inline_data.clone(),
-            ));
+                data.clone(),
+            );
+            self.meter.request_started();
+            self.reply_limited(get_block.hash, command, ctx);
             return;
         }
 
-        let (map, path) = match file_map.files.get(get_block.file_nr as usize) {
-            Some((ref map, ref path)) => (map, path),
+        if file_map.files.get(get_block.file_nr as usize).is_none() {
+            log::error!(
+                "invalid file_no: {} for {}",
+                get_block.file_nr,
+                get_block.hash
+            );
+            ctx.stop();
+            return;
+        }
+        let file_desc = file_map.clone();
+        let GetBlock {
+            hash,
+            file_nr,
+            block_nr,
+        } = get_block;
+
+        self.serve_from_disk(
+            hash,
+            move || {
+                let (map, path) = &file_desc.files[file_nr as usize];
+                read_block(path, map, block_nr)
+            },
+            move |bytes| StCommand::block(hash, file_nr, block_nr, bytes),
+            ctx,
+        );
+    }
+
+    /// Answers each requested block on its own, in a separate `Block` packet. Blocks are read
+    /// as request slots free up, like separate requests would be.
+    fn handle_get_blocks(&mut self, get_blocks: GetBlocks, ctx: &mut <Self as Actor>::Context) {
+        if get_blocks.blocks.len() > MAX_BATCH_BLOCKS {
+            log::error!(
+                "too many blocks in batch: {} for {}",
+                get_blocks.blocks.len(),
+                get_blocks.hash
+            );
+            ctx.stop();
+            return;
+        }
+        let hash = get_blocks.hash;
+        self.batched.extend(
+            get_blocks
+                .blocks
+                .into_iter()
+                .map(|(file_nr, block_nr)| GetBlock {
+                    hash,
+                    file_nr,
+                    block_nr,
+                }),
+        );
+        self.serve_batched(ctx);
+    }
+
+    /// Starts batched blocks while there are free request slots. Called whenever a request
+    /// completes asynchronously, inline blocks complete in place.
+    fn serve_batched(&mut self, ctx: &mut <Self as Actor>::Context) {
+        while self.meter.outstanding() < MAX_OUTSTANDING_REQUESTS
+            && ctx.state() == ActorState::Running
+        {
+            match self.batched.pop_front() {
+                Some(get_block) => self.handle_get_block(get_block, ctx),
+                None => break,
+            }
+        }
+    }
+
+    fn handle_get_range(&mut self, get_range: GetRange, ctx: &mut <Self as Actor>::Context) {
+        let file_desc = match &self.current_file {
+            Some(v) if v.map_hash == get_range.hash => v,
+            _ => {
+                log::error!("get range for not asked hash");
+                ctx.stop();
+                return;
+            }
+        };
+        if !file_desc.format.has_chunk_roots() {
+            log::error!("get range for flat resource {:032x}", get_range.hash);
+            ctx.stop();
+            return;
+        }
+        if file_desc.files.get(get_range.file_nr as usize).is_none() {
+            log::error!(
+                "invalid file_no: {} for {}",
+                get_range.file_nr,
+                get_range.hash
+            );
+            ctx.stop();
+            return;
+        }
+        let file_desc = file_desc.clone();
+        let GetRange {
+            hash,
+            file_nr,
+            offset,
+            len,
+        } = get_range;
+
+        self.serve_from_disk(
+            hash,
+            move || {
+                let (map, path) = &file_desc.files[file_nr as usize];
+                read_range(path, map, offset, len)
+            },
+            move |(chunks, proof)| {
+                StCommand::Range(Range {
+                    hash,
+                    file_nr,
+                    offset,
+                    len,
+                    chunks,
+                    proof,
+                })
+            },
+            ctx,
+        );
+    }
+
+    /// Reads on the blocking pool and writes the reply when done and allowed by upload limits.
+    /// Counts as an outstanding request until then.
+    fn serve_from_disk<T, R, W>(
+        &mut self,
+        hash: u128,
+        read: R,
+        reply: W,
+        ctx: &mut <Self as Actor>::Context,
+    ) where
+        R: FnOnce() -> Result<T, io::Error> + Send + 'static,
+        W: FnOnce(T) -> StCommand + 'static,
+        T: Send + 'static,
+    {
+        self.meter.request_started();
+        ctx.spawn(
+            reader::run(read)
+                .into_actor(self)
+                .then(move |r, act: &mut Self, ctx| {
+                    match r {
+                        Ok(v) => {
+                            act.reply_limited(hash, reply(v), ctx);
+                            act.serve_batched(ctx);
+                        }
+                        Err(e) => {
+                            act.meter.request_done();
+                            log::error!("read fail: {}", e);
+                            ctx.stop();
+                        }
+                    }
+                    fut::ok(())
+                }),
+        );
+    }
+
+    /// Writes the reply to a started request once allowed by upload limits.
+    fn reply_limited(
+        &mut self,
+        hash: u128,
+        command: StCommand,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let delay =
+            ratelimit::reserve_upload(self.peer_addr.ip(), hash, payload_len(&command) as u64);
+        if delay == Duration::from_secs(0) {
+            self.send_reply(command);
+        } else {
+            ctx.run_later(delay, move |act, ctx| {
+                act.send_reply(command);
+                act.serve_batched(ctx);
+            });
+        }
+    }
+
+    fn send_reply(&mut self, command: StCommand) {
+        self.meter.request_done();
+        self.framed.write(command);
+        self.apply_backpressure();
+    }
+
+    fn handle_range(&mut self, r: Range, _ctx: &mut <Self as Actor>::Context) {
+        if !self.range_requests.resolve(&r.request(), r) {
+            log::error!("response for not requested range");
+        }
+    }
+
+    fn handle_get_block_hashes(
+        &mut self,
+        get_hashes: GetBlockHashes,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let file_desc = match &self.current_file {
+            Some(v) if v.map_hash == get_hashes.hash => v,
+            _ => {
+                log::error!("get block hashes for not asked hash");
+                ctx.stop();
+                return;
+            }
+        };
+        let proof = file_desc
+            .files
+            .get(get_hashes.file_nr as usize)
+            .and_then(|(map, _path)| {
+                let proof =
+                    crate::filemap::block_hashes_proof(map, get_hashes.start, get_hashes.count)?;
+                let start = get_hashes.start as usize;
+                let hashes = map.blocks[start..start + get_hashes.count as usize].to_vec();
+                Some((hashes, proof))
+            });
+        let (hashes, proof) = match proof {
+            Some(v) => v,
             None => {
                 log::error!(
-                    "invalid file_no: {} for {}",
-                    get_block.file_nr,
-                    get_block.hash
+                    "invalid block hashes request file_no: {}, start: {}, count: {}",
+                    get_hashes.file_nr,
+                    get_hashes.start,
+                    get_hashes.count
                 );
                 ctx.stop();
                 return;
             }
         };
-        let bytes = match read_block(path, map, get_block.block_nr) {
-            Err(e) => {
-                log::error!("read fail: {}", e);
+
+        self.framed.write(StCommand::BlockHashes(BlockHashes {
+            hash: get_hashes.hash,
+            file_nr: get_hashes.file_nr,
+            start: get_hashes.start,
+            hashes,
+            proof,
+        }));
+    }
+
+    fn handle_block_hashes(&mut self, h: BlockHashes, _ctx: &mut <Self as Actor>::Context) {
+        if !self.hash_requests.resolve(&h.request(), h) {
+            log::error!("response for not requested block hashes");
+        }
+    }
+
+    fn handle_get_manifest_page(
+        &mut self,
+        get_page: GetManifestPage,
+        ctx: &mut <Self as Actor>::Context,
+    ) {
+        let file_desc = match &self.current_file {
+            Some(v) if v.map_hash == get_page.hash => v,
+            _ => {
+                log::error!("get manifest page for not asked hash");
                 ctx.stop();
                 return;
             }
-            Ok(bytes) => bytes,
         };
+        let first_file = get_page.first_file as usize;
+        if first_file >= file_desc.files.len() {
+            log::error!("invalid manifest page first_file: {}", first_file);
+            ctx.stop();
+            return;
+        }
+        let files = Manifest::page(
+            file_desc.format,
+            file_desc.files[first_file..].iter().map(|(map, _path)| map),
+            MAX_MANIFEST_PAGE_SIZE,
+        );
 
-        self.framed.write(StCommand::block(
-            get_block.hash,
-            get_block.file_nr,
-            get_block.block_nr,
-            bytes,
-        ));
+        self.framed.write(StCommand::ManifestPage(ManifestPage {
+            hash: get_page.hash,
+            first_file: get_page.first_file,
+            files,
+        }));
+    }
+
+    fn handle_manifest_page(&mut self, p: ManifestPage, _ctx: &mut <Self as Actor>::Context) {
+        if !self.page_requests.resolve(&p.request(), p) {
+            log::error!("response for not requested manifest page");
+        }
     }
 
     fn handle_block(&mut self, b: Block, _ctx: &mut <Self as Actor>::Context) {
@@ -267,33 +593,151 @@ impl Connection {
             file_nr: b.file_nr,
             block_nr: b.block_nr,
         };
-        if let Some(r) = self.block_requests.remove(&get_block) {
-            let _ = r.send(Ok(b));
-        } else {
+        if !self.block_requests.resolve(&get_block, b) {
             log::error!("response for not requested block");
         }
     }
 
     fn handle_ask_reply(&mut self, b: AskReply, _ctx: &mut <Self as Actor>::Context) {
-        if let Some(h) = self.ask_requests.remove(&b.hash) {
-            let _ = h.send(Ok(b));
-        } else {
+        let hash = b.hash;
+        if !self.ask_requests.resolve(&hash, b) {
             log::warn!("unexpected ask reply");
         }
     }
 
+    fn handle_challenge(
+        &mut self,
+        challenge: [u8; CHALLENGE_SIZE],
+        _ctx: &mut <Self as Actor>::Context,
+    ) {
+        self.framed.write(StCommand::Auth(Auth {
+            public_key: self.identity.public_key().to_vec(),
+            signature: self
+                .identity
+                .sign_challenge(&challenge, &self.handshake_hash),
+        }))
+    }
+
+    fn handle_auth(&mut self, auth: Auth, ctx: &mut <Self as Actor>::Context) {
+        let node_id = match self.peer_claim {
+            Some(node_id) => node_id,
+            None => {
+                log::error!("auth without hello from: {}", self.peer_addr);
+                return self.close_with_error(ProtocolError::MissingHandshake, ctx);
+            }
+        };
+        if identity::node_id(&auth.public_key) != node_id
+            || !identity::verify_challenge(
+                &auth.public_key,
+                &self.challenge,
+                &self.handshake_hash,
+                &auth.signature,
+            )
+        {
+            log::error!(
+                "peer {} failed to prove node id {:032x}",
+                self.peer_addr,
+                node_id
+            );
+            return self.close_with_error(ProtocolError::AuthFailed, ctx);
+        }
+        log::debug!("peer {} authenticated as {:032x}", self.peer_addr, node_id);
+        if reputation::is_banned(Some(node_id), self.peer_addr.ip()) {
+            log::warn!("banned peer {:032x} from {}", node_id, self.peer_addr);
+            self.framed.write(StCommand::Reject(RejectReason::Banned));
+            return self.close_with_error(ProtocolError::Rejected(RejectReason::Banned), ctx);
+        }
+        self.peer_id = Some(node_id);
+        if let Some(ticket) = &mut self.ticket {
+            ticket.handshake_done();
+        }
+        for sender in self.auth_requests.drain(..) {
+            let _ = sender.send(Ok(node_id));
+        }
+    }
+
+    /// Transfers in progress in either direction.
+    fn is_busy(&self) -> bool {
+        !self.auth_requests.is_empty()
+            || !self.block_requests.is_empty()
+            || !self.range_requests.is_empty()
+            || !self.hash_requests.is_empty()
+            || !self.page_requests.is_empty()
+            || !self.ask_requests.is_empty()
+            || !self.deferred.is_empty()
+            || !self.batched.is_empty()
+            || self.meter.outstanding() > 0
+            || self.meter.queued() > 0
+    }
+
+    /// Pings the peer, closes the connection when the peer went silent or nothing was
+    /// transferred for too long.
+    fn check_liveness(&mut self, ctx: &mut <Self as Actor>::Context) {
+        let busy = self.is_busy();
+        let reading = !self.meter.is_paused();
+        match self.liveness.check(Instant::now(), busy, reading) {
+            Verdict::Alive => {
+                if self.liveness.config().interval.is_some() {
+                    self.framed.write(StCommand::Nop)
+                }
+            }
+            Verdict::Dead => {
+                log::warn!(
+                    "[{}] peer {} not responding",
+                    self.connection_id,
+                    self.peer_addr
+                );
+                self.close_with_error(ProtocolError::PeerTimeout, ctx)
+            }
+            Verdict::Idle => {
+                log::info!("[{}] idle, closing", self.connection_id);
+                self.framed.write(StCommand::Bye);
+                self.close_with_error(ProtocolError::DisconnectByMe, ctx)
+            }
+        }
+    }
+
+    /// Writes request, or holds it while the peer is paused.
+    fn send_request(&mut self, command: StCommand) {
+        if self.peer_paused {
+            self.deferred.push_back(command);
+        } else {
+            self.framed.write(command)
+        }
+    }
+
+    fn handle_resume(&mut self) {
+        self.peer_paused = false;
+        while let Some(command) = self.deferred.pop_front() {
+            self.framed.write(command)
+        }
+    }
+
+    /// Stops reading from the peer when too much is queued for it.
+    fn apply_backpressure(&mut self) {
+        if !self.meter.is_paused() && self.meter.is_congested() {
+            log::debug!(
+                "[{}] pause reading, {} bytes queued",
+                self.connection_id,
+                self.meter.queued()
+            );
+            self.meter.pause();
+            self.framed.write(StCommand::Pause);
+        }
+    }
+
     fn close_with_error(&mut self, e: ProtocolError, ctx: &mut <Self as Actor>::Context) {
         self.reporter.emit_fail(&e);
-        std::mem::replace(&mut self.block_requests, HashMap::new())
-            .into_iter()
-            .for_each(|(_, sender)| {
-                let _ = sender.send(Err(e.into_err()));
-            });
-        std::mem::replace(&mut self.ask_requests, HashMap::new())
-            .into_iter()
-            .for_each(|(_, sender)| {
-                let _ = sender.send(Err(e.into_err()));
-            });
+        // Until authenticated the node id is only claimed, the address is charged alone.
+        reputation::report_error(self.peer_id, self.peer_addr.ip(), &e.into_err());
+        self.auth_requests.drain(..).for_each(|sender| {
+            let _ = sender.send(Err(e.into_err()));
+        });
+        self.block_requests.fail(&e);
+        self.range_requests.fail(&e);
+        self.hash_requests.fail(&e);
+        self.page_requests.fail(&e);
+        self.ask_requests.fail(&e);
         self.framed.close();
         ctx.run_later(Duration::from_millis(10), |_, ctx| {
             ctx.stop();
@@ -302,6 +746,48 @@ impl Connection {
     }
 }
 
+/// Callers waiting for replies to requests. Identical requests share one packet and its reply.
+struct Pending<K, T>(HashMap<K, Vec<oneshot::Sender<Result<T, Error>>>>);
+
+impl<K, T> Default for Pending<K, T> {
+    fn default() -> Self {
+        Pending(HashMap::new())
+    }
+}
+
+impl<K: std::hash::Hash + Eq, T: Clone> Pending<K, T> {
+    /// Registers a caller, returns whether the request has to be sent.
+    fn wait(&mut self, key: K) -> (bool, impl Future<Item = T, Error = Error>) {
+        let (tx, rx) = oneshot::channel();
+        let waiting = self.0.entry(key).or_default();
+        waiting.push(tx);
+        (waiting.len() == 1, rx.flatten())
+    }
+
+    /// Passes the reply to all callers, returns false if nobody waited for it.
+    fn resolve(&mut self, key: &K, reply: T) -> bool {
+        match self.0.remove(key) {
+            Some(waiting) => {
+                for tx in waiting {
+                    let _ = tx.send(Ok(reply.clone()));
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn fail(&mut self, e: &ProtocolError) {
+        for tx in self.0.drain().flat_map(|(_, waiting)| waiting) {
+            let _ = tx.send(Err(e.into_err()));
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
 fn read_block(
     path: impl AsRef<Path>,
     file_map: &FileMap,
@@ -313,34 +799,53 @@ fn read_block(
         block_no,
         file_map.file_name
     );
-    let offset = block_no as u64 * BLOCK_SIZE as u64;
-    if file_map.file_size < offset {
-        return Err(io::Error::new(ErrorKind::Other, "invalid offset"));
-    }
-    let size = min(file_map.file_size - offset, BLOCK_SIZE as u64) as usize;
-    let mut file = OpenOptions::new().read(true).open(path)?;
-    file.seek(SeekFrom::Start(offset))?;
-
-    let mut bytes_vec = Vec::with_capacity(size);
-    bytes_vec.resize(size, 0);
-
-    let mut bytes = bytes_vec.as_mut_slice();
-    while bytes.len() > 0 {
-        let n = file.read(bytes)?;
-        if n == 0 {
-            return Err(io::Error::new(
-                ErrorKind::UnexpectedEof,
-                "unexpected end of file",
-            ));
-        }
-        bytes = &mut bytes[n..];
+    let (offset, size) = file_map
+        .block_span(block_no as usize)
+        .ok_or_else(|| io::Error::other("invalid offset"))?;
+    let mut bytes_vec = vec![0; size];
+    if file_map.is_zero_block(block_no as usize) {
+        return Ok(bytes_vec);
     }
+    reader::read_exact_at(path.as_ref(), offset, &mut bytes_vec)?;
     Ok(bytes_vec)
 }
 
+fn payload_len(command: &StCommand) -> usize {
+    match command {
+        StCommand::Block(block) => block.bytes.len(),
+        StCommand::Range(range) => range.chunks.len(),
+        _ => 0,
+    }
+}
+
+/// Reads chunks covering a byte range together with their merkle proof.
+fn read_range(
+    path: impl AsRef<Path>,
+    file_map: &FileMap,
+    offset: u64,
+    len: u32,
+) -> Result<(Vec<u8>, Vec<u128>), io::Error> {
+    let span = RangeSpan::new(file_map, offset, len)
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "invalid range"))?;
+    let block = read_block(path, file_map, span.block_nr)?;
+    let proof = crate::merkle::range_proof(
+        &crate::filemap::chunk_hashes(&block),
+        span.first_chunk,
+        span.chunk_count,
+    );
+    let start = span.chunks_offset();
+    Ok((block[start..start + span.chunks_size()].to_vec(), proof))
+}
+
 impl StreamHandler<StCommand, io::Error> for Connection {
     fn handle(&mut self, item: StCommand, ctx: &mut Self::Context) {
         log::debug!("incomming packet={}", item.display());
+        let now = Instant::now();
+        self.liveness.received(now);
+        match item {
+            StCommand::Nop | StCommand::Pause | StCommand::Resume => (),
+            _ => self.liveness.active(now),
+        }
         match item {
             StCommand::Nop => (),
             StCommand::Bye => {
@@ -349,7 +854,7 @@ impl StreamHandler<StCommand, io::Error> for Connection {
             }
             StCommand::Hello(h) => {
                 if h.is_valid() {
-                    self.peer_id = Some(h.node_id);
+                    self.peer_claim = Some(h.node_id);
                 } else {
                     log::error!("invalid handshake from: {}", self.peer_addr);
                     self.close_with_error(ProtocolError::InvalidHandshake, ctx)
@@ -366,7 +871,52 @@ impl StreamHandler<StCommand, io::Error> for Connection {
             StCommand::AskReply(r) => self.handle_ask_reply(r, ctx),
             StCommand::GetBlock(b) => self.handle_get_block(b, ctx),
             StCommand::Block(b) => self.handle_block(b, ctx),
+            StCommand::GetBlocks(b) => self.handle_get_blocks(b, ctx),
+            StCommand::Challenge(c) => self.handle_challenge(c, ctx),
+            StCommand::Auth(a) => self.handle_auth(a, ctx),
+            StCommand::GetRange(r) => self.handle_get_range(r, ctx),
+            StCommand::Range(r) => self.handle_range(r, ctx),
+            StCommand::GetBlockHashes(h) => self.handle_get_block_hashes(h, ctx),
+            StCommand::BlockHashes(h) => self.handle_block_hashes(h, ctx),
+            StCommand::GetManifestPage(p) => self.handle_get_manifest_page(p, ctx),
+            StCommand::ManifestPage(p) => self.handle_manifest_page(p, ctx),
+            StCommand::Pause => self.peer_paused = true,
+            StCommand::Resume => self.handle_resume(),
+            StCommand::Reject(reason) => {
+                log::warn!("rejected by {}: {}", self.peer_addr, reason);
+                self.close_with_error(ProtocolError::Rejected(reason), ctx)
+            }
         }
+        self.apply_backpressure();
+    }
+
+    /// Malformed packets count against the peer.
+    fn error(&mut self, err: io::Error, _ctx: &mut Self::Context) -> Running {
+        log::error!("[{}] read error: {}", self.connection_id, err);
+        if err.kind() == ErrorKind::InvalidData {
+            reputation::report(
+                self.peer_id,
+                self.peer_addr.ip(),
+                reputation::Offense::ProtocolViolation,
+            );
+        }
+        Running::Stop
+    }
+
+    /// Peer closed the connection. Stopping is delayed so that errors already sent to pending
+    /// requests, like a rejection, reach the callers.
+    fn finished(&mut self, ctx: &mut Self::Context) {
+        ctx.run_later(Duration::from_millis(10), |_, ctx| {
+            ctx.stop();
+        });
+    }
+}
+
+/// Reading from the peer resumed after a pause.
+impl StreamHandler<(), ()> for Connection {
+    fn handle(&mut self, _: (), _ctx: &mut Self::Context) {
+        log::debug!("[{}] resume reading", self.connection_id);
+        self.framed.write(StCommand::Resume);
     }
 }
 
@@ -376,13 +926,11 @@ impl Handler<crate::codec::Ask> for Connection {
     type Result = ActorResponse<Self, AskReply, Error>;
 
     fn handle(&mut self, msg: crate::codec::Ask, _ctx: &mut Self::Context) -> Self::Result {
-        let (rx, tx) = oneshot::channel();
-        if let Some(_prev) = self.ask_requests.insert(msg.hash, rx) {
-            log::error!("duplicate ask");
-        } else {
-            self.framed.write(StCommand::Ask(msg.hash))
+        let (first, reply) = self.ask_requests.wait(msg.hash);
+        if first {
+            self.send_request(StCommand::Ask(msg.hash))
         }
-        ActorResponse::r#async(tx.flatten().into_actor(self))
+        ActorResponse::r#async(reply.into_actor(self))
     }
 }
 
@@ -390,22 +938,88 @@ impl Handler<crate::codec::GetBlock> for Connection {
     type Result = ActorResponse<Self, Block, Error>;
 
     fn handle(&mut self, msg: GetBlock, _ctx: &mut Self::Context) -> Self::Result {
-        let (rx, tx) = oneshot::channel();
-        if let Some(_prev) = self.block_requests.insert(msg.clone(), rx) {
-            log::error!("duplicate get");
-        } else {
-            self.framed.write(StCommand::GetBlock(msg))
+        let (first, reply) = self.block_requests.wait(msg.clone());
+        if first {
+            self.send_request(StCommand::GetBlock(msg))
         }
-        ActorResponse::r#async(tx.flatten().into_actor(self))
+        ActorResponse::r#async(reply.into_actor(self))
+    }
+}
+
+/// Blocks already asked for by other callers are not requested again.
+impl Handler<GetBlocks> for Connection {
+    type Result = ActorResponse<Self, Vec<Block>, Error>;
+
+    fn handle(&mut self, msg: GetBlocks, _ctx: &mut Self::Context) -> Self::Result {
+        let hash = msg.hash;
+        let mut blocks = Vec::new();
+        let mut replies = Vec::new();
+        for (file_nr, block_nr) in msg.blocks {
+            let (first, reply) = self.block_requests.wait(GetBlock {
+                hash,
+                file_nr,
+                block_nr,
+            });
+            if first {
+                blocks.push((file_nr, block_nr));
+            }
+            replies.push(reply);
+        }
+        if !blocks.is_empty() {
+            self.send_request(StCommand::GetBlocks(GetBlocks { hash, blocks }))
+        }
+        ActorResponse::r#async(futures::future::join_all(replies).into_actor(self))
+    }
+}
+
+impl Handler<GetRange> for Connection {
+    type Result = ActorResponse<Self, Range, Error>;
+
+    fn handle(&mut self, msg: GetRange, _ctx: &mut Self::Context) -> Self::Result {
+        let (first, reply) = self.range_requests.wait(msg.clone());
+        if first {
+            self.send_request(StCommand::GetRange(msg))
+        }
+        ActorResponse::r#async(reply.into_actor(self))
+    }
+}
+
+impl Handler<GetBlockHashes> for Connection {
+    type Result = ActorResponse<Self, BlockHashes, Error>;
+
+    fn handle(&mut self, msg: GetBlockHashes, _ctx: &mut Self::Context) -> Self::Result {
+        let (first, reply) = self.hash_requests.wait(msg.clone());
+        if first {
+            self.send_request(StCommand::GetBlockHashes(msg))
+        }
+        ActorResponse::r#async(reply.into_actor(self))
+    }
+}
+
+impl Handler<GetManifestPage> for Connection {
+    type Result = ActorResponse<Self, ManifestPage, Error>;
+
+    fn handle(&mut self, msg: GetManifestPage, _ctx: &mut Self::Context) -> Self::Result {
+        let (first, reply) = self.page_requests.wait(msg.clone());
+        if first {
+            self.send_request(StCommand::GetManifestPage(msg))
+        }
+        ActorResponse::r#async(reply.into_actor(self))
     }
 }
 
 impl Handler<crate::codec::Hello> for Connection {
-    type Result = Result<(), Error>;
+    type Result = ActorResponse<Self, u128, Error>;
 
     fn handle(&mut self, msg: crate::codec::Hello, _ctx: &mut Self::Context) -> Self::Result {
         self.framed.write(StCommand::hello(msg.node_id));
-        Ok(())
+        self.framed.write(StCommand::Challenge(self.challenge));
+        if let Some(peer_id) = self.peer_id {
+            return ActorResponse::reply(Ok(peer_id));
+        }
+        let (rx, tx) = oneshot::channel();
+        self.auth_requests.push(rx);
+        ActorResponse::r#async(tx.flatten().into_actor(self))
     }
 }
 
@@ -427,18 +1041,500 @@ impl Handler<crate::codec::Bye> for Connection {
     }
 }
 
-pub struct ConnectionRef(Addr<Connection>);
+pub struct ConnectionRef {
+    addr: Addr<Connection>,
+    peer_id: u128,
+}
+
+impl ConnectionRef {
+    /// Node id proven by the peer.
+    pub fn peer_id(&self) -> u128 {
+        self.peer_id
+    }
+}
 
 impl Deref for ConnectionRef {
     type Target = Addr<Connection>;
 
     fn deref(&self) -> &Self::Target {
-        &self.0
+        &self.addr
     }
 }
 
 impl Drop for ConnectionRef {
     fn drop(&mut self) {
-        self.0.do_send(crate::codec::Bye::new());
+        self.addr.do_send(crate::codec::Bye::new());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::codec::Ask;
+    use crate::filemap::{Manifest, MapFormat, BLOCK_SIZE};
+    use crate::user_report::UserReportHandle;
+    use futures::future;
+    use std::path::PathBuf;
+    use tokio_tcp::{TcpListener, TcpStream};
+
+    fn temp_db(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hyperg-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Starts plaintext connection and resolves once the peer has proven its node id.
+    fn start(
+        db: Addr<DatabaseManager>,
+        io: TcpStream,
+        peer_addr: net::SocketAddr,
+    ) -> impl Future<Item = Addr<Connection>, Error = Error> {
+        Connection::start(
+            db,
+            Transport::Plain(io),
+            peer_addr,
+            None,
+            &UserReportHandle::empty(),
+        )
+        .map(|(addr, _)| addr)
+    }
+
+    /// Connects two nodes over loopback, resolves to server and client side connections.
+    fn connect_pair(
+        server_db: Addr<DatabaseManager>,
+        client_db: Addr<DatabaseManager>,
+    ) -> impl Future<Item = (Addr<Connection>, Addr<Connection>), Error = Error> {
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = listener
+            .incoming()
+            .into_future()
+            .map_err(|(e, _)| e.into())
+            .and_then(move |(io, _)| start(server_db, io.unwrap(), addr));
+        let client = TcpStream::connect(&addr)
+            .from_err()
+            .and_then(move |io| start(client_db, io, addr));
+
+        server.join(client)
+    }
+
+    /// Shares files returned by `files` from a new server node in `format` and connects a new
+    /// client to it. Resolves to the resource hash and the client side connection.
+    fn serve<F>(
+        name: &str,
+        format: MapFormat,
+        files: F,
+    ) -> impl Future<Item = (u128, Addr<Connection>), Error = Error>
+    where
+        F: FnOnce(&Path) -> Vec<(FileMap, PathBuf)>,
+    {
+        serve_pair(name, format, files).map(|(hash, _server, client)| (hash, client))
+    }
+
+    /// Like `serve`, also resolves to the server side connection.
+    fn serve_pair<F>(
+        name: &str,
+        format: MapFormat,
+        files: F,
+    ) -> impl Future<Item = (u128, Addr<Connection>, Addr<Connection>), Error = Error>
+    where
+        F: FnOnce(&Path) -> Vec<(FileMap, PathBuf)>,
+    {
+        let server_dir = temp_db(&format!("{}-server", name));
+        let client_dir = temp_db(&format!("{}-client", name));
+        let files = files(&server_dir);
+
+        future::lazy(move || {
+            let server_db = database::database_manager(&Some(server_dir));
+            server_db
+                .send(database::RegisterHash {
+                    files,
+                    valid_to: None,
+                    reporter: UserReportHandle::empty(),
+                    format,
+                })
+                .flatten()
+                .and_then(move |hash| {
+                    connect_pair(server_db, database::database_manager(&Some(client_dir)))
+                        .map(move |(server, client)| (hash, server, client))
+                })
+        })
+    }
+
+    /// Writes `files` to `dir` and splits them the way `format` expects.
+    fn write_files(
+        dir: &Path,
+        format: MapFormat,
+        files: Vec<(String, Vec<u8>)>,
+    ) -> Vec<(FileMap, PathBuf)> {
+        files
+            .into_iter()
+            .map(|(name, data)| {
+                let path = dir.join(&name);
+                std::fs::write(&path, data).unwrap();
+                let file_map = crate::filemap::hash_file_as(format, &path, name).unwrap();
+                (file_map, path)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_authenticated_handshake() {
+        let server_dir = temp_db("auth-server");
+        let client_dir = temp_db("auth-client");
+
+        let f = future::lazy(move || {
+            connect_pair(
+                database::database_manager(&Some(server_dir)),
+                database::database_manager(&Some(client_dir)),
+            )
+        });
+
+        assert!(actix::System::new("test").block_on(f).is_ok());
+    }
+
+    #[test]
+    fn test_rejected() {
+        let client_dir = temp_db("rejected-client");
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let f = future::lazy(move || {
+            let mut buf = bytes::BytesMut::new();
+            tokio_io::codec::Encoder::encode(
+                &mut StCodec::default(),
+                StCommand::Reject(RejectReason::AddressLimit),
+                &mut buf,
+            )
+            .unwrap();
+            Arbiter::spawn(listener.incoming().into_future().map_err(|_| ()).and_then(
+                move |(io, _)| {
+                    // Rejects once the client hello arrived.
+                    tokio_io::io::read_exact(io.unwrap(), [0u8; 18])
+                        .and_then(move |(io, _)| tokio_io::io::write_all(io, buf))
+                        .map(|_| ())
+                        .map_err(|_| ())
+                },
+            ));
+            TcpStream::connect(&addr)
+                .from_err()
+                .and_then(move |io| start(database::database_manager(&Some(client_dir)), io, addr))
+        });
+
+        match actix::System::new("test").block_on(f) {
+            Err(Error::ProtocolError(ProtocolError::Rejected(reason))) => {
+                assert_eq!(reason, RejectReason::AddressLimit)
+            }
+            _ => panic!("connection not rejected"),
+        }
+    }
+
+    #[test]
+    fn test_get_range() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + 100_000).map(|i| (i % 241) as u8).collect();
+        let (offset, len) = (BLOCK_SIZE as u64 + 70_000, 1000u32);
+        let contents = data.clone();
+
+        let f = serve("range", MapFormat::Tree, move |dir| {
+            write_files(dir, MapFormat::Tree, vec![("data.bin".into(), contents)])
+        })
+        .and_then(move |(hash, client)| {
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .and_then(move |reply| {
+                    crate::download::file_maps(client.clone(), hash, reply.files.unwrap())
+                        .map(move |file_maps| (file_maps, client))
+                })
+                .and_then(move |(file_maps, client)| {
+                    client
+                        .send(GetRange {
+                            hash,
+                            file_nr: 0,
+                            offset,
+                            len,
+                        })
+                        .flatten()
+                        .map(move |range| (file_maps, range))
+                })
+        });
+
+        let (file_maps, range) = actix::System::new("test").block_on(f).unwrap();
+        let bytes =
+            crate::filemap::verify_range(&file_maps[0], offset, len, &range.chunks, &range.proof)
+                .unwrap();
+        assert_eq!(
+            bytes,
+            &data[offset as usize..offset as usize + len as usize]
+        );
+        assert!(range.chunks.len() < BLOCK_SIZE / 2);
+    }
+
+    #[test]
+    fn test_shared_requests() {
+        let data: Vec<u8> = (0..100_000).map(|i| (i % 239) as u8).collect();
+        let contents = data.clone();
+
+        let f = serve("shared", MapFormat::Flat, move |dir| {
+            write_files(dir, MapFormat::Flat, vec![("data.bin".into(), contents)])
+        })
+        .and_then(move |(hash, client)| {
+            let get_block = move || GetBlock {
+                hash,
+                file_nr: 0,
+                block_nr: 0,
+            };
+            // Both callers get the reply of a single request.
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .join(client.send(Ask::new(hash)).flatten())
+                .and_then(move |_| {
+                    client
+                        .send(get_block())
+                        .flatten()
+                        .join(client.send(get_block()).flatten())
+                })
+        });
+
+        let (first, second) = actix::System::new("test").block_on(f).unwrap();
+        assert_eq!(first.bytes, data);
+        assert_eq!(second.bytes, data);
+    }
+
+    #[test]
+    fn test_get_blocks() {
+        let f = serve("batch", MapFormat::Flat, |dir| {
+            // One more file than fits in a batch.
+            let files = (0..=MAX_BATCH_BLOCKS)
+                .map(|i| (format!("small-{}", i), vec![i as u8; 1000 + i]))
+                .collect();
+            write_files(dir, MapFormat::Flat, files)
+        })
+        .and_then(move |(hash, client)| {
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .and_then(move |_| {
+                    // Block of file 0 is shared with the single block request.
+                    client
+                        .send(GetBlocks {
+                            hash,
+                            blocks: vec![(2, 0), (0, 0), (3, 0)],
+                        })
+                        .flatten()
+                        .join(
+                            client
+                                .send(GetBlock {
+                                    hash,
+                                    file_nr: 0,
+                                    block_nr: 0,
+                                })
+                                .flatten(),
+                        )
+                        .map(move |replies| (replies, client))
+                })
+                .and_then(move |(replies, client)| {
+                    // Server drops peers asking for too many blocks at once.
+                    let blocks = (0..=MAX_BATCH_BLOCKS as u32).map(|nr| (nr, 0)).collect();
+                    client
+                        .send(GetBlocks { hash, blocks })
+                        .flatten()
+                        .then(move |oversized| Ok((replies, oversized)))
+                })
+        });
+
+        let ((blocks, single), oversized) = actix::System::new("test").block_on(f).unwrap();
+        let file_nrs: Vec<u32> = blocks.iter().map(|b| b.file_nr).collect();
+        assert_eq!(file_nrs, vec![2, 0, 3]);
+        for b in &blocks {
+            assert_eq!(b.bytes, vec![b.file_nr as u8; 1000 + b.file_nr as usize]);
+        }
+        assert_eq!(single.bytes, blocks[1].bytes);
+        assert!(oversized.is_err());
+    }
+
+    /// Serves a batch as if read from the peer, resolves to the requests started and still
+    /// queued right after.
+    struct ServeBatch(GetBlocks);
+
+    impl Message for ServeBatch {
+        type Result = (usize, usize);
+    }
+
+    impl Handler<ServeBatch> for Connection {
+        type Result = MessageResult<ServeBatch>;
+
+        fn handle(&mut self, msg: ServeBatch, ctx: &mut Self::Context) -> Self::Result {
+            self.handle_get_blocks(msg.0, ctx);
+            MessageResult((self.meter.outstanding(), self.batched.len()))
+        }
+    }
+
+    #[test]
+    fn test_batch_outstanding() {
+        let count = MAX_OUTSTANDING_REQUESTS * 2;
+        let f = serve_pair("batch-cap", MapFormat::Flat, move |dir| {
+            let files = (0..count)
+                .map(|i| (format!("file-{}", i), vec![i as u8; 50_000]))
+                .collect();
+            write_files(dir, MapFormat::Flat, files)
+        })
+        .and_then(move |(hash, server, client)| {
+            let blocks: Vec<(u32, u32)> = (0..count as u32).map(|nr| (nr, 0)).collect();
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .and_then(move |_| {
+                    server
+                        .send(ServeBatch(GetBlocks {
+                            hash,
+                            blocks: blocks.clone(),
+                        }))
+                        .from_err()
+                        .map(move |started| (started, blocks))
+                })
+                .and_then(move |(started, blocks)| {
+                    // Queued blocks are still served once earlier reads complete.
+                    client
+                        .send(GetBlocks { hash, blocks })
+                        .flatten()
+                        .map(move |replies| (started, replies))
+                })
+        });
+
+        let ((outstanding, queued), replies) = actix::System::new("test").block_on(f).unwrap();
+        assert_eq!(outstanding, MAX_OUTSTANDING_REQUESTS);
+        assert_eq!(queued, count - MAX_OUTSTANDING_REQUESTS);
+        assert_eq!(replies.len(), count);
+    }
+
+    #[test]
+    fn test_tree_manifest() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 2 + 10).map(|i| (i % 239) as u8).collect();
+        let expected: Vec<u128> = data
+            .chunks(BLOCK_SIZE)
+            .map(crate::filemap::chunk_root)
+            .collect();
+        let contents = data.clone();
+
+        let f = serve("tree", MapFormat::Tree, move |dir| {
+            write_files(dir, MapFormat::Tree, vec![("data.bin".into(), contents)])
+        })
+        .and_then(move |(hash, client)| {
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .and_then(move |reply| {
+                    crate::download::file_maps(client, hash, reply.files.unwrap())
+                })
+        });
+
+        let file_maps = actix::System::new("test").block_on(f).unwrap();
+        assert_eq!(file_maps.len(), 1);
+        assert_eq!(file_maps[0].file_size, data.len() as u64);
+        assert_eq!(file_maps[0].blocks, expected);
+    }
+
+    #[test]
+    fn test_inline_files() {
+        let f = serve("inline", MapFormat::Flat, |dir| {
+            let files = (0..3)
+                .map(|i| {
+                    (
+                        format!("config-{}", i),
+                        format!("config {}", i).into_bytes(),
+                    )
+                })
+                .collect();
+            let mut files = write_files(dir, MapFormat::Flat, files);
+            for (file_map, path) in &mut files {
+                file_map.inline = Some(std::fs::read(&path).unwrap());
+                // Inline contents are served from memory.
+                std::fs::write(&path, b"changed").unwrap();
+            }
+            files
+        })
+        .and_then(move |(hash, client)| {
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .and_then(move |reply| {
+                    let manifest = reply.files.unwrap();
+                    let tampered = match &manifest {
+                        Manifest::Inline(payloads, files) => {
+                            assert_eq!(payloads.len(), 3);
+                            Manifest::Inline(vec![(1, b"config 0".to_vec())], files.clone())
+                        }
+                        _ => panic!("expected inline manifest"),
+                    };
+                    let block = client
+                        .send(GetBlock {
+                            hash,
+                            file_nr: 2,
+                            block_nr: 0,
+                        })
+                        .flatten();
+                    crate::download::file_maps(client.clone(), hash, manifest)
+                        .join(crate::download::file_maps(client, hash, tampered).then(Ok))
+                        .join(block)
+                })
+        });
+
+        let ((file_maps, tampered), block) = actix::System::new("test").block_on(f).unwrap();
+        assert_eq!(file_maps.len(), 3);
+        for (i, file_map) in file_maps.iter().enumerate() {
+            assert_eq!(file_map.inline, Some(format!("config {}", i).into_bytes()));
+        }
+        assert!(matches!(tampered, Err(Error::InvalidManifest(_))));
+        assert_eq!(block.bytes, b"config 2".to_vec());
+    }
+
+    #[test]
+    fn test_paged_manifest() {
+        // Block hash lists alone are too big for one page, files are never read.
+        let files: Vec<FileMap> = (0..2u32)
+            .map(|i| FileMap {
+                file_name: format!("huge-{}", i),
+                file_size: BLOCK_SIZE as u64 * 300_000,
+                blocks: (0..300_000u128).map(|b| b + i as u128).collect(),
+                chunks: Vec::new(),
+                meta: None,
+                inline: None,
+            })
+            .collect();
+        let expected = files.clone();
+
+        let f = serve("paged", MapFormat::Flat, move |dir| {
+            files
+                .into_iter()
+                .map(|file_map| {
+                    let path = dir.join(&file_map.file_name);
+                    (file_map, path)
+                })
+                .collect()
+        })
+        .and_then(move |(hash, client)| {
+            client
+                .send(Ask::new(hash))
+                .flatten()
+                .and_then(move |reply| {
+                    let manifest = reply.files.unwrap();
+                    match manifest {
+                        Manifest::Paged(ref header) => assert_eq!(header.file_count, 2),
+                        _ => panic!("expected paged manifest"),
+                    }
+                    crate::download::file_maps(client, hash, manifest)
+                })
+        });
+
+        let file_maps = actix::System::new("test").block_on(f).unwrap();
+        assert_eq!(file_maps.len(), 2);
+        for (file_map, expected) in file_maps.iter().zip(&expected) {
+            assert_eq!(file_map.file_name, expected.file_name);
+            assert_eq!(file_map.blocks, expected.blocks);
+        }
     }
 }
@@ -1,15 +1,15 @@
 use crate::error::Error;
-use crate::filemap::FileMap;
+use crate::filemap::{self, FileMap, Manifest, MapFormat};
+use crate::identity::Identity;
 use crate::user_report::UserReportHandle;
 use actix::prelude::*;
-use rand::Rng;
 use serde::{Deserialize, Serialize};
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
-use std::{fs, path, time};
+use std::{fs, io, path, time};
 
 /// metadata format
 const FORMAT_VERSION: u32 = 1;
@@ -18,7 +18,7 @@ const FORMAT_VERSION: u32 = 1;
 struct Meta {
     /// Metadata format version
     format: u32,
-    /// Node id
+    /// Node id, derived from the node key
     id: u128,
     /// Reserved for future use
     flags: Vec<String>,
@@ -28,11 +28,25 @@ struct Meta {
 pub struct FileDesc {
     pub map_hash: u128,
     pub files: Vec<(FileMap, PathBuf)>,
-    pub inline_data: Vec<u8>,
     pub valid_to: Option<time::SystemTime>,
+    pub format: MapFormat,
 }
 
 impl FileDesc {
+    /// Drops cached handles of shared files.
+    fn close_files(&self) {
+        for (_, file_path) in &self.files {
+            crate::reader::close(file_path);
+        }
+    }
+
+    pub fn manifest(&self) -> Manifest {
+        Manifest::new(
+            self.format,
+            self.files.iter().map(|(file_map, _path)| file_map),
+        )
+    }
+
     #[inline]
     fn log_event(&self, event_name: &str) {
         for (_, file_path) in &self.files {
@@ -46,24 +60,102 @@ impl FileDesc {
     }
 }
 
+/// Where a block can be read from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockLocation {
+    pub path: PathBuf,
+    pub offset: u64,
+    pub size: usize,
+}
+
+impl BlockLocation {
+    pub fn read(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = vec![0; self.size];
+        crate::reader::read_exact_at(&self.path, self.offset, &mut bytes)?;
+        Ok(bytes)
+    }
+}
+
+/// Blocks of `files` with where to read them from.
+pub fn block_locations(
+    files: &[(FileMap, PathBuf)],
+) -> impl Iterator<Item = (u128, BlockLocation)> + '_ {
+    files.iter().flat_map(|(file_map, path)| {
+        file_map
+            .blocks
+            .iter()
+            .enumerate()
+            .filter_map(move |(block_nr, block_hash)| {
+                let (offset, size) = file_map.block_span(block_nr)?;
+                let location = BlockLocation {
+                    path: path.clone(),
+                    offset,
+                    size,
+                };
+                Some((*block_hash, location))
+            })
+    })
+}
+
+/// Shared blocks by hash, each tagged with the resource it belongs to.
+#[derive(Default)]
+struct BlockIndex(HashMap<u128, Vec<(u128, BlockLocation)>>);
+
+impl BlockIndex {
+    fn add(&mut self, desc: &FileDesc) {
+        for (block_hash, location) in block_locations(&desc.files) {
+            self.0
+                .entry(block_hash)
+                .or_default()
+                .push((desc.map_hash, location));
+        }
+    }
+
+    fn remove(&mut self, desc: &FileDesc) {
+        for block_hash in desc.files.iter().flat_map(|(file_map, _)| &file_map.blocks) {
+            if let Entry::Occupied(mut entry) = self.0.entry(*block_hash) {
+                entry.get_mut().retain(|(owner, _)| *owner != desc.map_hash);
+                if entry.get().is_empty() {
+                    entry.remove();
+                }
+            }
+        }
+    }
+
+    fn find(&self, block_hash: u128) -> Option<&BlockLocation> {
+        self.0
+            .get(&block_hash)
+            .and_then(|locations| locations.first())
+            .map(|(_, location)| location)
+    }
+}
+
 pub struct DatabaseManager {
     dir: PathBuf,
-    id: Option<u128>,
+    identity: Option<Arc<Identity>>,
     files: HashMap<u128, (Arc<FileDesc>, UserReportHandle)>,
+    blocks: BlockIndex,
 }
 
 impl DatabaseManager {
     fn load_hash(&mut self, p: &path::Path) -> Result<(), Error> {
         let desc: FileDesc = bincode::deserialize_from(fs::OpenOptions::new().read(true).open(p)?)?;
         desc.log_event("reshare");
+        self.blocks.add(&desc);
         self.files
             .insert(desc.map_hash, (Arc::new(desc), UserReportHandle::empty()));
         Ok(())
     }
 
-    fn init(&mut self) -> Result<(), Error> {
+    fn load_identity(&mut self) -> Result<u128, Error> {
+        let identity = Identity::load_or_create(&self.dir.join("node.key"))?;
+        let id = identity.node_id();
+        self.identity = Some(Arc::new(identity));
+        Ok(id)
+    }
+
+    fn write_meta(&self, id: u128) -> Result<(), Error> {
         let meta_path = self.dir.join("meta");
-        let id: u128 = rand::thread_rng().gen();
         let meta = Meta {
             format: FORMAT_VERSION,
             id,
@@ -77,10 +169,14 @@ impl DatabaseManager {
                 .open(meta_path)?,
             &meta,
         )?;
-        self.id = Some(meta.id);
         Ok(())
     }
 
+    fn init(&mut self) -> Result<(), Error> {
+        let id = self.load_identity()?;
+        self.write_meta(id)
+    }
+
     fn load(&mut self) -> Result<(), Error> {
         let meta = self.dir.join("meta");
         if meta.exists() {
@@ -91,7 +187,11 @@ impl DatabaseManager {
                     detected_version: meta_def.format,
                 });
             }
-            self.id = Some(meta_def.id)
+            let id = self.load_identity()?;
+            if meta_def.id != id {
+                // Node ids used to be random, now they are bound to the node key.
+                self.write_meta(id)?;
+            }
         } else {
             return Err(Error::MetadataNotFound);
         }
@@ -128,6 +228,8 @@ impl DatabaseManager {
         for hash in expired_file_hashes {
             if let Some((file_desc, _)) = self.files.remove(&hash) {
                 file_desc.log_event("unshare");
+                file_desc.close_files();
+                self.blocks.remove(&file_desc);
             }
         }
     }
@@ -153,7 +255,10 @@ impl Actor for DatabaseManager {
             }
             Ok(()) => (),
         }
-        log::info!("db started id=0x{:032x}", self.id.as_ref().unwrap());
+        log::info!(
+            "db started id=0x{:032x}",
+            self.identity.as_ref().unwrap().node_id()
+        );
     }
 }
 
@@ -162,16 +267,22 @@ static APP_INFO: app_dirs::AppInfo = app_dirs::AppInfo {
     author: "golem.network",
 };
 
-pub fn database_manager(cache_path: &Option<PathBuf>) -> Addr<DatabaseManager> {
-    let dir = cache_path.clone().unwrap_or_else(|| {
+/// Database directory, the default one when `cache_path` is not set.
+pub fn db_dir(cache_path: &Option<PathBuf>) -> PathBuf {
+    cache_path.clone().unwrap_or_else(|| {
         app_dirs::app_dir(app_dirs::AppDataType::UserCache, &APP_INFO, "db").unwrap()
-    });
+    })
+}
+
+pub fn database_manager(cache_path: &Option<PathBuf>) -> Addr<DatabaseManager> {
+    let dir = db_dir(cache_path);
 
     let addr = SyncArbiter::start(1, move || {
         let man = DatabaseManager {
             dir: dir.clone(),
             files: HashMap::new(),
-            id: None,
+            identity: None,
+            blocks: BlockIndex::default(),
         };
 
         man
@@ -181,22 +292,24 @@ pub fn database_manager(cache_path: &Option<PathBuf>) -> Addr<DatabaseManager> {
     addr
 }
 
-struct GetId;
+struct GetIdentity;
 
-impl Message for GetId {
-    type Result = Result<u128, Error>;
+impl Message for GetIdentity {
+    type Result = Result<Arc<Identity>, Error>;
 }
 
-impl Handler<GetId> for DatabaseManager {
-    type Result = Result<u128, Error>;
+impl Handler<GetIdentity> for DatabaseManager {
+    type Result = Result<Arc<Identity>, Error>;
 
-    fn handle(&mut self, _msg: GetId, _ctx: &mut Self::Context) -> Self::Result {
-        self.id.clone().ok_or(Error::ServiceFail("DatabaseManager"))
+    fn handle(&mut self, _msg: GetIdentity, _ctx: &mut Self::Context) -> Self::Result {
+        self.identity
+            .clone()
+            .ok_or(Error::ServiceFail("DatabaseManager"))
     }
 }
 
-pub fn id(m: &Addr<DatabaseManager>) -> impl Future<Item = u128, Error = Error> {
-    m.send(GetId).then(|r| match r {
+pub fn identity(m: &Addr<DatabaseManager>) -> impl Future<Item = Arc<Identity>, Error = Error> {
+    m.send(GetIdentity).then(|r| match r {
         Ok(r) => r,
         Err(e) => Err(e.into()),
     })
@@ -233,6 +346,8 @@ impl Handler<RemoveHash> for DatabaseManager {
         let prev = self.files.remove(&msg.0);
         Ok(if let Some((file_desc, _)) = prev {
             file_desc.log_event("unshare");
+            file_desc.close_files();
+            self.blocks.remove(&file_desc);
             Some(file_desc)
         } else {
             None
@@ -243,8 +358,8 @@ impl Handler<RemoveHash> for DatabaseManager {
 pub struct RegisterHash {
     pub files: Vec<(FileMap, PathBuf)>,
     pub valid_to: Option<time::SystemTime>,
-    pub inline_data: Vec<u8>,
     pub reporter: UserReportHandle,
+    pub format: MapFormat,
 }
 
 impl Message for RegisterHash {
@@ -255,13 +370,13 @@ impl Handler<RegisterHash> for DatabaseManager {
     type Result = Result<u128, Error>;
 
     fn handle(&mut self, msg: RegisterHash, _ctx: &mut Self::Context) -> Self::Result {
-        let map_hash = crate::filemap::hash_bundles(msg.files.iter().map(|(map, _path)| map));
+        let map_hash = filemap::map_hash(msg.format, msg.files.iter().map(|(map, _path)| map));
         let reporter = msg.reporter;
         let desc = Arc::new(FileDesc {
             map_hash,
             files: msg.files,
-            inline_data: msg.inline_data,
             valid_to: msg.valid_to.clone(),
+            format: msg.format,
         });
 
         match self.files.entry(map_hash) {
@@ -273,11 +388,14 @@ impl Handler<RegisterHash> for DatabaseManager {
                     _ => false,
                 };
                 if !old_is_longer {
+                    self.blocks.remove(&prev_ent.0);
+                    self.blocks.add(&desc);
                     prev_ent.0 = desc.clone();
                     desc.log_event("share extend");
                 }
             }
             Entry::Vacant(ent) => {
+                self.blocks.add(&desc);
                 ent.insert((desc.clone(), reporter));
                 desc.log_event("share");
             }
@@ -301,6 +419,29 @@ impl Handler<List> for DatabaseManager {
     }
 }
 
+/// Looks up local copies of blocks, hashes not found are left out.
+pub struct FindBlocks(pub Vec<u128>);
+
+impl Message for FindBlocks {
+    type Result = HashMap<u128, BlockLocation>;
+}
+
+impl Handler<FindBlocks> for DatabaseManager {
+    type Result = MessageResult<FindBlocks>;
+
+    fn handle(&mut self, msg: FindBlocks, _: &mut Self::Context) -> Self::Result {
+        MessageResult(
+            msg.0
+                .into_iter()
+                .filter_map(|block_hash| {
+                    let location = self.blocks.find(block_hash)?;
+                    Some((block_hash, location.clone()))
+                })
+                .collect(),
+        )
+    }
+}
+
 struct Gc;
 
 impl Message for Gc {
@@ -333,3 +474,51 @@ impl Actor for GcWorker {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::filemap::BLOCK_SIZE;
+
+    fn desc(map_hash: u128, path: &str, blocks: Vec<u128>) -> FileDesc {
+        FileDesc {
+            map_hash,
+            files: vec![(
+                FileMap {
+                    file_name: path.into(),
+                    file_size: BLOCK_SIZE as u64 * blocks.len() as u64 - 10,
+                    blocks,
+                    chunks: Vec::new(),
+                    meta: None,
+                    inline: None,
+                },
+                path.into(),
+            )],
+            valid_to: None,
+            format: MapFormat::Flat,
+        }
+    }
+
+    #[test]
+    fn test_block_index() {
+        let a = desc(1, "/tmp/a", vec![10, 11]);
+        let b = desc(2, "/tmp/b", vec![11, 12]);
+        let mut index = BlockIndex::default();
+        index.add(&a);
+        index.add(&b);
+
+        // First resource holding the block is read from.
+        let first = index.find(11).unwrap();
+        assert_eq!(first.path, PathBuf::from("/tmp/a"));
+        assert_eq!(first.offset, BLOCK_SIZE as u64);
+        assert_eq!(first.size, BLOCK_SIZE - 10);
+        assert_eq!(index.find(12).unwrap().size, BLOCK_SIZE - 10);
+
+        // Block still shared by another resource stays.
+        index.remove(&a);
+        assert!(index.find(10).is_none());
+        assert_eq!(index.find(11).unwrap().path, PathBuf::from("/tmp/b"));
+        index.remove(&b);
+        assert!(index.0.is_empty());
+    }
+}
@@ -1,24 +1,34 @@
 #![allow(unused_imports)]
 
-use crate::codec::{Ask, AskReply};
+use crate::codec::{Ask, AskReply, GetBlockHashes, GetManifestPage};
 use crate::connection::{Connection, ConnectionRef};
 use crate::database::DatabaseManager;
 use crate::error::Error;
-use crate::filemap::FileMap;
+use crate::filemap::{self, FileHeader, FileMap, Manifest, ManifestHeader};
+use crate::pool::{self, Lease};
+use crate::reputation;
+use crate::transport::{self, TransportConfig};
 use actix::prelude::*;
+use futures::future;
 use futures::prelude::*;
 use std::net;
+use std::sync::Arc;
 
 use failure::_core::time::Duration;
+use std::cmp::min;
 use tokio_tcp::{ConnectFuture, TcpStream};
 
+/// Number of block hashes requested at once when resolving a tree manifest.
+const BLOCK_HASHES_BATCH: u32 = 16 * 1024;
+
 pub fn connect(
     db: Addr<DatabaseManager>,
     addr: net::SocketAddr,
+    transport: Arc<TransportConfig>,
     reporter: crate::user_report::UserReportHandle,
 ) -> impl Future<Item = ConnectionRef, Error = Error> {
-    TcpStream::connect(&addr).from_err().and_then(move |c| {
-        reporter.add_note(|| format!("connected to {}", addr));
+    transport::connect(addr, transport).and_then(move |c| {
+        reporter.add_note(|| format!("connected to {} encrypted={}", addr, c.is_encrypted()));
         Connection::new_managed(db, c, addr, &reporter)
     })
 }
@@ -27,30 +37,263 @@ pub fn find_peer(
     hash: u128,
     db: Addr<DatabaseManager>,
     addr: Vec<net::SocketAddr>,
+    transport: Arc<TransportConfig>,
     reporter: crate::user_report::UserReportHandle,
-) -> impl Future<Item = (ConnectionRef, Vec<FileMap>, net::SocketAddr), Error = Error> {
+) -> impl Future<Item = (Lease, Vec<FileMap>, net::SocketAddr), Error = Error> {
     let connections = addr.into_iter().map(move |addr| {
         let hash = hash;
         let reporter = reporter.clone();
 
-        reporter.add_note(|| format!("connecting to {}", addr));
-
-        connect(db.clone(), addr, reporter.clone())
-            .and_then(move |connection| {
-                connection
-                    .send(Ask::new(hash))
-                    .flatten()
-                    .and_then(move |reply: AskReply| match reply.files {
-                        Some(files) => Ok((connection, files, addr)),
-                        None => Err(Error::ResourceNotFound(reply.hash)),
-                    })
-            })
-            .map_err(move |e| {
-                reporter.add_err(|| format!("failed to connect to {}: {}", addr, e));
+        if reputation::is_banned(None, addr.ip()) {
+            reporter.add_err(|| format!("skipping banned peer {}", addr));
+            return future::Either::A(future::err(Error::PeerBanned(addr)));
+        }
+        let connection = match pool::acquire(addr, hash) {
+            Some(lease) => {
+                reporter.add_note(|| format!("reusing connection to {}", addr));
+                future::Either::A(future::ok(lease))
+            }
+            None => {
+                reporter.add_note(|| format!("connecting to {}", addr));
+                future::Either::B(
+                    connect(db.clone(), addr, transport.clone(), reporter.clone())
+                        .map(move |connection| pool::insert(connection, addr, hash)),
+                )
+            }
+        };
 
-                e
-            })
+        future::Either::B(
+            connection
+                .and_then(move |connection| {
+                    let peer_id = connection.peer_id();
+                    if reputation::is_banned(Some(peer_id), addr.ip()) {
+                        return future::Either::A(future::err(Error::PeerBanned(addr)));
+                    }
+                    future::Either::B(
+                        connection
+                            .send(Ask::new(hash))
+                            .flatten()
+                            .and_then(move |reply: AskReply| match reply.files {
+                                Some(manifest) => Ok(manifest),
+                                None => Err(Error::ResourceNotFound(reply.hash)),
+                            })
+                            .and_then(move |manifest| {
+                                file_maps((*connection).clone(), hash, manifest)
+                                    .map(move |files| (connection, files, addr))
+                            })
+                            .map_err(move |e| {
+                                reputation::report_error(Some(peer_id), addr.ip(), &e);
+                                e
+                            }),
+                    )
+                })
+                .map_err(move |e| {
+                    reporter.add_err(|| format!("failed to connect to {}: {}", addr, e));
+
+                    e
+                }),
+        )
     });
 
     futures::select_ok(connections).and_then(|(v, _)| Ok(v))
 }
+
+/// Asks each peer for resource `hash`. Resolves to file maps from a peer with a verified manifest
+/// and addresses of all peers that sent one, fails when none did.
+pub fn inspect(
+    hash: u128,
+    db: Addr<DatabaseManager>,
+    addr: Vec<net::SocketAddr>,
+    transport: Arc<TransportConfig>,
+    reporter: crate::user_report::UserReportHandle,
+) -> impl Future<Item = (Vec<FileMap>, Vec<net::SocketAddr>), Error = Error> {
+    let asks = addr.into_iter().map(move |addr| {
+        find_peer(
+            hash,
+            db.clone(),
+            vec![addr],
+            transport.clone(),
+            reporter.clone(),
+        )
+        .then(move |r| Ok::<_, Error>((addr, r.map(|(_, files, _)| files))))
+    });
+
+    future::join_all(asks).and_then(move |replies| {
+        let mut file_maps = None;
+        let mut answered = Vec::new();
+        let mut error = Error::ResourceNotFound(hash);
+        for (addr, reply) in replies {
+            match reply {
+                Ok(files) => {
+                    answered.push(addr);
+                    file_maps.get_or_insert(files);
+                }
+                Err(e) => error = e,
+            }
+        }
+        file_maps.map(|files| (files, answered)).ok_or(error)
+    })
+}
+
+/// Checks manifest against resource hash. Pages of paged manifest and block hashes of tree
+/// manifest are fetched from the peer, block hashes in batches verified against file root.
+pub fn file_maps(
+    connection: Addr<Connection>,
+    hash: u128,
+    manifest: Manifest,
+) -> Box<dyn Future<Item = Vec<FileMap>, Error = Error>> {
+    if manifest.map_hash() != hash {
+        return Box::new(future::err(Error::InvalidManifest(hash)));
+    }
+    resolve_maps(connection, hash, manifest)
+}
+
+/// File maps of a manifest already checked against `hash`.
+fn resolve_maps(
+    connection: Addr<Connection>,
+    hash: u128,
+    manifest: Manifest,
+) -> Box<dyn Future<Item = Vec<FileMap>, Error = Error>> {
+    match manifest {
+        Manifest::Paged(header) => Box::new(
+            fetch_manifest(connection.clone(), header)
+                .and_then(move |manifest| file_maps(connection, hash, manifest)),
+        ),
+        Manifest::Flat(file_maps) => Box::new(future::ok(file_maps)),
+        Manifest::Inline(payloads, manifest) => {
+            if matches!(*manifest, Manifest::Paged(_)) {
+                return Box::new(future::err(Error::InvalidManifest(hash)));
+            }
+            Box::new(
+                resolve_maps(connection, hash, *manifest).and_then(move |mut file_maps| {
+                    for (file_nr, data) in payloads {
+                        let file_map = file_maps
+                            .get_mut(file_nr as usize)
+                            .filter(|file_map| {
+                                file_map.blocks.len() == 1
+                                    && file_map.file_size == data.len() as u64
+                                    && filemap::verify_block(&data, file_map.blocks[0])
+                            })
+                            .ok_or(Error::InvalidManifest(hash))?;
+                        file_map.inline = Some(data);
+                    }
+                    Ok(file_maps)
+                }),
+            )
+        }
+        Manifest::Meta(metas, manifest) => {
+            if metas.len() != manifest.len() || matches!(*manifest, Manifest::Paged(_)) {
+                return Box::new(future::err(Error::InvalidManifest(hash)));
+            }
+            Box::new(
+                resolve_maps(connection, hash, *manifest).map(move |file_maps| {
+                    file_maps
+                        .into_iter()
+                        .zip(metas)
+                        .map(|(file_map, meta)| FileMap { meta, ..file_map })
+                        .collect()
+                }),
+            )
+        }
+        Manifest::Cdc(entries) => Box::new(
+            filemap::chunked_maps(entries)
+                .ok_or(Error::InvalidManifest(hash))
+                .into_future(),
+        ),
+        Manifest::Tree(headers) => Box::new(
+            futures::stream::iter_ok(headers.into_iter().enumerate())
+                .and_then(move |(file_nr, header)| {
+                    fetch_block_hashes(connection.clone(), hash, file_nr as u32, header)
+                })
+                .collect(),
+        ),
+    }
+}
+
+fn fetch_block_hashes(
+    connection: Addr<Connection>,
+    hash: u128,
+    file_nr: u32,
+    header: FileHeader,
+) -> impl Future<Item = FileMap, Error = Error> {
+    let block_count = header.block_count() as u32;
+    let batches: Vec<_> = (0..block_count)
+        .step_by(BLOCK_HASHES_BATCH as usize)
+        .map(|start| (start, min(BLOCK_HASHES_BATCH, block_count - start)))
+        .collect();
+    let header = Arc::new(header);
+    let batch_header = header.clone();
+
+    futures::stream::iter_ok(batches)
+        .and_then(move |(start, count)| {
+            let header = batch_header.clone();
+            connection
+                .send(GetBlockHashes {
+                    hash,
+                    file_nr,
+                    start,
+                    count,
+                })
+                .flatten()
+                .and_then(move |reply| {
+                    if filemap::verify_block_hashes(&header, start, &reply.hashes, &reply.proof) {
+                        Ok(reply.hashes)
+                    } else {
+                        Err(Error::InvalidManifest(hash))
+                    }
+                })
+        })
+        .concat2()
+        .and_then(move |blocks| {
+            let file_map = FileMap {
+                file_name: header.file_name.clone(),
+                file_size: header.file_size,
+                blocks,
+                chunks: Vec::new(),
+                meta: None,
+                inline: None,
+            };
+            // Empty files have no blocks to prove.
+            if file_map.root() == header.root {
+                Ok(file_map)
+            } else {
+                Err(Error::InvalidManifest(hash))
+            }
+        })
+}
+
+/// Collects manifest pages, the result is checked against `header`.
+fn fetch_manifest(
+    connection: Addr<Connection>,
+    header: ManifestHeader,
+) -> impl Future<Item = Manifest, Error = Error> {
+    let hash = header.map_hash;
+    let file_count = header.file_count as usize;
+    let empty = Manifest::page(header.format, None, 0);
+
+    future::loop_fn(empty, move |mut manifest| {
+        connection
+            .send(GetManifestPage {
+                hash,
+                first_file: manifest.len() as u32,
+            })
+            .flatten()
+            .and_then(move |page| {
+                if page.files.is_empty() || !manifest.append(page.files) {
+                    return Err(Error::InvalidManifest(hash));
+                }
+                Ok(if manifest.len() < file_count {
+                    future::Loop::Continue(manifest)
+                } else {
+                    future::Loop::Break(manifest)
+                })
+            })
+    })
+    .and_then(move |manifest| {
+        if manifest.header() == header {
+            Ok(manifest)
+        } else {
+            Err(Error::InvalidManifest(hash))
+        }
+    })
+}
@@ -17,6 +17,21 @@ pub enum ProtocolError {
 
     #[fail(display = "handshake timeout")]
     HandshakeTimeout,
+
+    #[fail(display = "peer not responding")]
+    PeerTimeout,
+
+    #[fail(display = "peer requires encryption")]
+    EncryptionRequired,
+
+    #[fail(display = "encryption disabled")]
+    EncryptionDisabled,
+
+    #[fail(display = "peer authentication failed")]
+    AuthFailed,
+
+    #[fail(display = "rejected: {}", _0)]
+    Rejected(crate::codec::RejectReason),
 }
 
 impl ProtocolError {
@@ -37,6 +52,8 @@ pub enum Error {
     InvalidMetaVersion { detected_version: u32 },
     #[fail(display = "matadata not found")]
     MetadataNotFound,
+    #[fail(display = "invalid node key")]
+    InvalidNodeKey,
     #[fail(display = "{} not working", _0)]
     ServiceFail(&'static str),
     #[fail(display = "{}", _0)]
@@ -47,8 +64,24 @@ pub enum Error {
     ResourceNotFound(u128),
     #[fail(display = "invalid block hash {:032x}", _0)]
     InvalidBlockHash(u128),
+    #[fail(display = "manifest does not match resource {:032x}", _0)]
+    InvalidManifest(u128),
+    #[fail(display = "invalid proof for range at {}", _0)]
+    InvalidRangeProof(u64),
+    #[fail(display = "range at {} is outside of the file", _0)]
+    InvalidRange(u64),
+    #[fail(display = "peer {} is banned", _0)]
+    PeerBanned(std::net::SocketAddr),
+    #[fail(display = "invalid hash: {}", _0)]
+    InvalidHash(#[cause] std::num::ParseIntError),
+    #[fail(display = "file {} not found in resource", _0)]
+    FileNotFound(String),
+    #[fail(display = "invalid file name {:?}", _0)]
+    InvalidFileName(String),
     #[fail(display = "{}", _0)]
     ProtocolError(#[cause] ProtocolError),
+    #[fail(display = "noise: {}", _0)]
+    Noise(#[cause] snow::Error),
 }
 
 macro_rules! convert {
@@ -69,5 +102,7 @@ convert! {
     serde_json::Error => InvalidJsonFormat,
     actix::MailboxError => Mailbox,
     futures::Canceled => RequestCanceled,
-    ProtocolError => ProtocolError
+    std::num::ParseIntError => InvalidHash,
+    ProtocolError => ProtocolError,
+    snow::Error => Noise
 }
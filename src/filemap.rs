@@ -1,19 +1,483 @@
+use crate::{cdc, merkle};
 use serde::{Deserialize, Serialize};
 use sha2::digest::Digest;
 use std::borrow::Borrow;
 use std::cmp::min;
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, UNIX_EPOCH};
 use std::{fs, io};
 
 pub const BLOCK_SIZE: usize = 1024 * 1024 * 4;
 
+/// Tree and cdc block hashes are merkle roots over chunks of this size, so parts of a block
+/// can be verified on their own.
+pub const CHUNK_SIZE: usize = 1024 * 64;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct FileMap {
     pub file_name: String,
     pub file_size: u64,
     pub blocks: Vec<u128>,
+    /// Start offsets of content-defined chunks, empty when the file is split into `BLOCK_SIZE`
+    /// blocks. Only the cdc manifest carries them.
+    #[serde(skip)]
+    pub chunks: Vec<u64>,
+    /// Only sent in a metadata section of the manifest, see `Manifest::Meta`.
+    #[serde(skip)]
+    pub meta: Option<FileMeta>,
+    /// Content of a small single block file, sent along with the manifest.
+    #[serde(skip)]
+    pub inline: Option<Vec<u8>>,
+}
+
+/// File attributes restored on download when enabled on both ends.
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+pub struct FileMeta {
+    /// Unix permission bits.
+    pub mode: Option<u32>,
+    /// Modification time in seconds since the unix epoch.
+    pub mtime: Option<i64>,
+    /// Target of a symlink, `/` separated. The entry then has no content.
+    pub symlink: Option<String>,
+}
+
+impl FileMeta {
+    pub fn new(metadata: &fs::Metadata) -> Self {
+        #[cfg(unix)]
+        let mode = {
+            use std::os::unix::fs::PermissionsExt;
+            Some(metadata.permissions().mode() & 0o777)
+        };
+        #[cfg(not(unix))]
+        let mode = None;
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs() as i64);
+        FileMeta {
+            mode,
+            mtime,
+            symlink: None,
+        }
+    }
+
+    /// Metadata of `path` itself, symlinks are not followed.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let metadata = fs::symlink_metadata(path)?;
+        if !metadata.file_type().is_symlink() {
+            return Ok(Self::new(&metadata));
+        }
+        let target = fs::read_link(path)?;
+        let target = target.to_str().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("symlink target {:?} is not valid unicode", target),
+            )
+        })?;
+        Ok(FileMeta {
+            symlink: Some(target.replace(std::path::MAIN_SEPARATOR, "/")),
+            ..FileMeta::default()
+        })
+    }
+
+    /// Sets mode and modification time of `path`. Setuid and similar bits are never set.
+    pub fn apply(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            if let Some(mode) = self.mode {
+                fs::set_permissions(path, fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        }
+        if let Some(mtime) = self.mtime.filter(|_| path.is_file()) {
+            let mtime = if mtime >= 0 {
+                UNIX_EPOCH + Duration::from_secs(mtime as u64)
+            } else {
+                UNIX_EPOCH - Duration::from_secs(mtime.unsigned_abs())
+            };
+            fs::OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_modified(mtime)?;
+        }
+        Ok(())
+    }
+
+    /// Creates `path` as a symlink to `target`.
+    pub fn symlink(target: &str, path: &Path) -> io::Result<()> {
+        let target: PathBuf = target.split('/').collect();
+        #[cfg(unix)]
+        return std::os::unix::fs::symlink(target, path);
+        #[cfg(windows)]
+        return std::os::windows::fs::symlink_file(target, path);
+        #[cfg(not(any(unix, windows)))]
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!("cannot create symlink {}", path.display()),
+        ));
+    }
+}
+
+impl FileMap {
+    /// Entry without content, an empty directory (named with a trailing `/`) or a symlink.
+    pub fn empty(file_name: impl Into<String>) -> Self {
+        FileMap {
+            file_name: file_name.into(),
+            file_size: 0,
+            blocks: Vec::new(),
+            chunks: Vec::new(),
+            meta: None,
+            inline: None,
+        }
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_name.ends_with('/')
+    }
+
+    pub fn symlink(&self) -> Option<&str> {
+        self.meta.as_ref()?.symlink.as_deref()
+    }
+
+    /// Offset and size of block `block_nr`.
+    pub fn block_span(&self, block_nr: usize) -> Option<(u64, usize)> {
+        if block_nr >= self.blocks.len() {
+            return None;
+        }
+        let (offset, end) = if self.chunks.is_empty() {
+            let offset = block_nr as u64 * BLOCK_SIZE as u64;
+            (offset, min(offset + BLOCK_SIZE as u64, self.file_size))
+        } else {
+            let end = self.chunks.get(block_nr + 1).copied();
+            (self.chunks[block_nr], end.unwrap_or(self.file_size))
+        };
+        Some((offset, end.saturating_sub(offset) as usize))
+    }
+
+    /// Block holding byte at `offset`.
+    pub fn block_at(&self, offset: u64) -> Option<usize> {
+        if offset >= self.file_size {
+            return None;
+        }
+        if self.chunks.is_empty() {
+            return Some((offset / BLOCK_SIZE as u64) as usize);
+        }
+        Some(self.chunks.partition_point(|start| *start <= offset) - 1)
+    }
+
+    /// Whether block `block_nr` holds only zeros. Such blocks are known by their hash, so they
+    /// are never transferred and written as holes.
+    pub fn is_zero_block(&self, block_nr: usize) -> bool {
+        match self.block_span(block_nr) {
+            Some((_, size)) => {
+                let hash = self.blocks[block_nr];
+                hash == zero_block_hash(MapFormat::Tree, size)
+                    || hash == zero_block_hash(MapFormat::Flat, size)
+            }
+            None => false,
+        }
+    }
+
+    /// Chunk offsets start at zero, grow, stay within the file and chunks fit in a block.
+    fn chunks_valid(&self) -> bool {
+        if self.chunks.is_empty() {
+            return true;
+        }
+        self.chunks.len() == self.blocks.len()
+            && self.chunks[0] == 0
+            && self
+                .chunks
+                .iter()
+                .zip(self.chunks[1..].iter().chain(Some(&self.file_size)))
+                .all(|(start, end)| start < end && end - start <= BLOCK_SIZE as u64)
+    }
+
+    /// Merkle root of block hashes, commits to the whole file in the tree format.
+    pub fn root(&self) -> u128 {
+        file_root(&self.blocks)
+    }
+
+    pub fn header(&self) -> FileHeader {
+        FileHeader {
+            file_name: self.file_name.clone(),
+            file_size: self.file_size,
+            root: self.root(),
+        }
+    }
+}
+
+/// File entry of a tree format manifest. Block hashes are requested separately.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct FileHeader {
+    pub file_name: String,
+    pub file_size: u64,
+    pub root: u128,
+}
+
+impl FileHeader {
+    pub fn block_count(&self) -> usize {
+        block_count(self.file_size)
+    }
+}
+
+/// Manifest format of a blob, selects what blob hash commits to.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MapFormat {
+    /// Blob hash covers every block hash, whole manifest is sent at once.
+    #[default]
+    Flat = 1,
+    /// Blob hash covers per-file merkle roots, block hashes are fetched with proofs.
+    Tree = 2,
+    /// Like flat, with content-defined chunks instead of fixed blocks.
+    Cdc = 3,
+}
+
+impl MapFormat {
+    /// Whether block hashes are merkle roots over chunks, so byte ranges of blocks can be
+    /// proven. Flat blocks keep the plain hash older peers expect.
+    pub fn has_chunk_roots(self) -> bool {
+        self != MapFormat::Flat
+    }
+
+    pub fn hash_block(self, block: &[u8]) -> u128 {
+        if self.has_chunk_roots() {
+            chunk_root(block)
+        } else {
+            hash_block(block)
+        }
+    }
+
+    /// Format in which `maps` hash to `hash`.
+    pub fn of<'a>(
+        hash: u128,
+        maps: impl IntoIterator<Item = &'a FileMap> + Clone,
+    ) -> Option<MapFormat> {
+        [MapFormat::Flat, MapFormat::Tree, MapFormat::Cdc]
+            .iter()
+            .copied()
+            .find(|format| map_hash(*format, maps.clone()) == hash)
+    }
+}
+
+/// Blob manifest as sent to peers, tagged with its format.
+#[derive(Serialize, Deserialize, Clone)]
+pub enum Manifest {
+    Flat(Vec<FileMap>),
+    Tree(Vec<FileHeader>),
+    /// Manifest too big for a single packet, entries are fetched in pages.
+    Paged(ManifestHeader),
+    /// File maps with their chunk offsets.
+    Cdc(Vec<(FileMap, Vec<u64>)>),
+    /// Manifest of files with metadata, one entry per file.
+    Meta(Vec<Option<FileMeta>>, Box<Manifest>),
+    /// Manifest with contents of small files by file number. Contents are checked against
+    /// block hashes, so they do not change the blob hash.
+    Inline(Vec<(u32, Vec<u8>)>, Box<Manifest>),
+}
+
+/// Summary of a paged manifest. Pages put together must match it.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ManifestHeader {
+    pub format: MapFormat,
+    pub file_count: u32,
+    pub total_size: u64,
+    pub map_hash: u128,
+}
+
+impl Manifest {
+    pub fn new<'a>(format: MapFormat, maps: impl IntoIterator<Item = &'a FileMap>) -> Self {
+        Self::page(format, maps, u64::MAX)
+    }
+
+    /// Adds contents of inlined `maps` while the manifest stays within `max_size` bytes.
+    pub fn with_inline<'a>(
+        self,
+        maps: impl IntoIterator<Item = &'a FileMap>,
+        max_size: u64,
+    ) -> Self {
+        // Variant tag and payload count.
+        let mut size = bincode::serialized_size(&self).unwrap() + 12;
+        let mut payloads = Vec::new();
+        for (file_nr, map) in maps.into_iter().enumerate() {
+            if let Some(data) = &map.inline {
+                let entry_size = bincode::serialized_size(&(0u32, data)).unwrap();
+                if size + entry_size > max_size {
+                    break;
+                }
+                size += entry_size;
+                payloads.push((file_nr as u32, data.clone()));
+            }
+        }
+        if payloads.is_empty() {
+            return self;
+        }
+        Manifest::Inline(payloads, Box::new(self))
+    }
+
+    /// Entries for leading `maps` that fit in `max_size` bytes, at least one.
+    pub fn page<'a>(
+        format: MapFormat,
+        maps: impl IntoIterator<Item = &'a FileMap>,
+        max_size: u64,
+    ) -> Self {
+        let maps: Vec<&FileMap> = maps.into_iter().collect();
+        let with_meta = maps.iter().any(|map| map.meta.is_some());
+        let mut manifest = match format {
+            MapFormat::Flat => Manifest::Flat(Vec::new()),
+            MapFormat::Tree => Manifest::Tree(Vec::new()),
+            MapFormat::Cdc => Manifest::Cdc(Vec::new()),
+        };
+        let mut size = bincode::serialized_size(&manifest).unwrap();
+        for map in maps.iter().copied() {
+            let mut entry_size = match &mut manifest {
+                Manifest::Flat(maps) => {
+                    maps.push(map.clone());
+                    bincode::serialized_size(map).unwrap()
+                }
+                Manifest::Tree(headers) => {
+                    let header = map.header();
+                    let entry_size = bincode::serialized_size(&header).unwrap();
+                    headers.push(header);
+                    entry_size
+                }
+                Manifest::Cdc(entries) => {
+                    let entry = (map.clone(), map.chunks.clone());
+                    let entry_size = bincode::serialized_size(&entry).unwrap();
+                    entries.push(entry);
+                    entry_size
+                }
+                Manifest::Paged(_) | Manifest::Meta(..) | Manifest::Inline(..) => unreachable!(),
+            };
+            if with_meta {
+                entry_size += bincode::serialized_size(&map.meta).unwrap();
+            }
+            size += entry_size;
+            if size > max_size && manifest.len() > 1 {
+                manifest.truncate(manifest.len() - 1);
+                break;
+            }
+        }
+        let maps = &maps[..manifest.len()];
+        if maps.iter().any(|map| map.meta.is_some()) {
+            let metas = maps.iter().map(|map| map.meta.clone()).collect();
+            manifest = Manifest::Meta(metas, Box::new(manifest));
+        }
+        manifest
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Manifest::Flat(maps) => maps.len(),
+            Manifest::Tree(headers) => headers.len(),
+            Manifest::Cdc(entries) => entries.len(),
+            Manifest::Paged(header) => header.file_count as usize,
+            Manifest::Meta(_, manifest) | Manifest::Inline(_, manifest) => manifest.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn truncate(&mut self, len: usize) {
+        match self {
+            Manifest::Flat(maps) => maps.truncate(len),
+            Manifest::Tree(headers) => headers.truncate(len),
+            Manifest::Cdc(entries) => entries.truncate(len),
+            Manifest::Paged(_) => (),
+            Manifest::Meta(metas, manifest) => {
+                metas.truncate(len);
+                manifest.truncate(len);
+            }
+            Manifest::Inline(payloads, manifest) => {
+                payloads.retain(|(file_nr, _)| (*file_nr as usize) < len);
+                manifest.truncate(len);
+            }
+        }
+    }
+
+    /// Appends entries of a page. Fails if formats differ. Pages may come with or without a
+    /// metadata section, files without one get no metadata.
+    pub fn append(&mut self, page: Manifest) -> bool {
+        let (page_metas, page) = match page {
+            Manifest::Meta(metas, page) => (Some(metas), *page),
+            page => (None, page),
+        };
+        if page_metas.is_some() && !matches!(self, Manifest::Meta(..)) {
+            let manifest = std::mem::replace(self, Manifest::Flat(Vec::new()));
+            *self = Manifest::Meta(vec![None; manifest.len()], Box::new(manifest));
+        }
+        let page_len = page.len();
+        match self {
+            Manifest::Meta(metas, manifest) => {
+                if !manifest.append_entries(page) {
+                    return false;
+                }
+                metas.extend(page_metas.unwrap_or_else(|| vec![None; page_len]));
+                true
+            }
+            manifest => manifest.append_entries(page),
+        }
+    }
+
+    fn append_entries(&mut self, page: Manifest) -> bool {
+        match (self, page) {
+            (Manifest::Flat(maps), Manifest::Flat(page)) => maps.extend(page),
+            (Manifest::Tree(headers), Manifest::Tree(page)) => headers.extend(page),
+            (Manifest::Cdc(entries), Manifest::Cdc(page)) => entries.extend(page),
+            _ => return false,
+        }
+        true
+    }
+
+    pub fn map_hash(&self) -> u128 {
+        match self {
+            Manifest::Flat(maps) => hash_bundles(maps),
+            Manifest::Tree(headers) => hash_headers(headers),
+            Manifest::Cdc(entries) => {
+                hash_chunked(entries.iter().map(|(map, chunks)| (map, chunks.as_slice())))
+            }
+            Manifest::Paged(header) => header.map_hash,
+            Manifest::Meta(metas, manifest) => {
+                hash_meta(manifest.map_hash(), metas.iter().map(Option::as_ref))
+            }
+            Manifest::Inline(_, manifest) => manifest.map_hash(),
+        }
+    }
+
+    pub fn header(&self) -> ManifestHeader {
+        let (format, total_size) = match self {
+            Manifest::Flat(maps) => (MapFormat::Flat, maps.iter().map(|map| map.file_size).sum()),
+            Manifest::Tree(headers) => (
+                MapFormat::Tree,
+                headers.iter().map(|header| header.file_size).sum(),
+            ),
+            Manifest::Cdc(entries) => (
+                MapFormat::Cdc,
+                entries.iter().map(|(map, _)| map.file_size).sum(),
+            ),
+            Manifest::Paged(header) => return header.clone(),
+            Manifest::Meta(_, manifest) => {
+                return ManifestHeader {
+                    map_hash: self.map_hash(),
+                    ..manifest.header()
+                }
+            }
+            Manifest::Inline(_, manifest) => return manifest.header(),
+        };
+        ManifestHeader {
+            format,
+            file_count: self.len() as u32,
+            total_size,
+            map_hash: self.map_hash(),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -27,16 +491,16 @@ fn extract_results<D: Digest>(digest: D) -> u128 {
     u128::from_le_bytes(digest.result()[0..16].try_into().unwrap())
 }
 
-pub fn hash_file(
+/// Splits the file into `BLOCK_SIZE` blocks hashed the way `format` expects.
+fn hash_file_blocks(
+    format: MapFormat,
     path: impl AsRef<Path>,
     file_name: impl Into<String>,
 ) -> Result<FileMap, io::Error> {
     let mut file = fs::OpenOptions::new().read(true).open(path)?;
     let file_size = file.metadata()?.len();
     let file_name = file_name.into();
-    let num_of_blocks = ((file_size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64)
-        .try_into()
-        .unwrap();
+    let num_of_blocks = block_count(file_size);
 
     let mut buf = Vec::with_capacity(BLOCK_SIZE);
     buf.resize(BLOCK_SIZE, 0);
@@ -47,32 +511,250 @@ pub fn hash_file(
 
     let mut rem_file_bytes = file_size;
     while rem_file_bytes > 0 {
-        let mut rem_block_bytes = BLOCK_SIZE;
-        let mut digest = sha2::Sha224::new();
-        while rem_block_bytes > 0 && rem_file_bytes > 0 {
-            let to_read = min(buf.len(), rem_block_bytes);
-            let len = file.read(&mut buf[..to_read])?;
+        let block_size = min(rem_file_bytes, BLOCK_SIZE as u64) as usize;
+        let mut pos = 0;
+        while pos < block_size {
+            let len = file.read(&mut buf[pos..block_size])?;
 
             if len == 0 {
                 return Err(io::Error::new(io::ErrorKind::Other, "Unexpected EOF"));
             }
-
-            rem_block_bytes -= len;
-            rem_file_bytes -= len as u64;
-            digest.input(&buf[0..len]);
+            pos += len;
         }
-        let hash = extract_results(digest);
+        rem_file_bytes -= block_size as u64;
 
-        blocks.push(hash);
+        blocks.push(hash_data_block(format, &buf[..block_size]));
     }
 
     Ok(FileMap {
         file_name,
         file_size,
         blocks,
+        chunks: Vec::new(),
+        meta: None,
+        inline: None,
     })
 }
 
+/// Splits the file into blocks the way `format` expects.
+pub fn hash_file_as(
+    format: MapFormat,
+    path: impl AsRef<Path>,
+    file_name: impl Into<String>,
+) -> Result<FileMap, io::Error> {
+    match format {
+        MapFormat::Cdc => hash_file_cdc(path, file_name),
+        MapFormat::Flat | MapFormat::Tree => hash_file_blocks(format, path, file_name),
+    }
+}
+
+/// Like `hash_file_blocks`, with content-defined chunks instead of fixed blocks.
+pub fn hash_file_cdc(
+    path: impl AsRef<Path>,
+    file_name: impl Into<String>,
+) -> Result<FileMap, io::Error> {
+    let mut file = fs::OpenOptions::new().read(true).open(path)?;
+    let mut buf = Vec::with_capacity(cdc::MAX_SIZE * 2);
+    let mut blocks = Vec::new();
+    let mut chunks = Vec::new();
+    let mut offset = 0u64;
+    let mut eof = false;
+
+    loop {
+        if !eof && buf.len() < cdc::MAX_SIZE {
+            let want = (cdc::MAX_SIZE - buf.len()) as u64;
+            eof = (&mut file).take(want).read_to_end(&mut buf)? < want as usize;
+        }
+        if buf.is_empty() {
+            break;
+        }
+        let len = cdc::cut(&buf);
+        blocks.push(hash_data_block(MapFormat::Cdc, &buf[..len]));
+        chunks.push(offset);
+        offset += len as u64;
+        buf.drain(..len);
+    }
+
+    Ok(FileMap {
+        file_name: file_name.into(),
+        file_size: offset,
+        blocks,
+        chunks,
+        meta: None,
+        inline: None,
+    })
+}
+
+/// Regular files under `dir`, recursively, in a stable order.
+pub fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(list_files(&entry.path())?);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+/// Include and exclude globs for directory uploads, matched against paths relative to the
+/// uploaded directory.
+#[derive(Default)]
+pub struct PathFilter {
+    include: Vec<glob::Pattern>,
+    exclude: Vec<glob::Pattern>,
+}
+
+impl PathFilter {
+    pub fn new(include: &[String], exclude: &[String]) -> Result<Self, glob::PatternError> {
+        let compile = |patterns: &[String]| {
+            patterns
+                .iter()
+                .map(|pattern| glob::Pattern::new(pattern))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        Ok(PathFilter {
+            include: compile(include)?,
+            exclude: compile(exclude)?,
+        })
+    }
+
+    fn excludes(&self, path: &str) -> bool {
+        self.exclude.iter().any(|pattern| pattern.matches(path))
+    }
+
+    /// Files and empty directories have to match an include pattern, if there are any.
+    fn includes(&self, path: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches(path))
+    }
+}
+
+/// Files of a resource picked for download by exact name or glob pattern, all files when there
+/// are no patterns.
+#[derive(Clone, Default)]
+pub struct FileSelection(Vec<(String, glob::Pattern)>);
+
+impl FileSelection {
+    pub fn new(patterns: &[String]) -> Result<Self, glob::PatternError> {
+        patterns
+            .iter()
+            .map(|pattern| Ok((pattern.clone(), glob::Pattern::new(pattern)?)))
+            .collect::<Result<_, _>>()
+            .map(FileSelection)
+    }
+
+    pub fn is_all(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn includes(&self, file_name: &str) -> bool {
+        self.is_all()
+            || self
+                .0
+                .iter()
+                .any(|(name, pattern)| name == file_name || pattern.matches(file_name))
+    }
+
+    /// Patterns naming at least one of `file_maps`.
+    pub fn matched<'a>(&self, file_maps: impl Iterator<Item = &'a FileMap> + Clone) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|(name, pattern)| {
+                file_maps.clone().any(|file_map| {
+                    name == &file_map.file_name || pattern.matches(&file_map.file_name)
+                })
+            })
+            .map(|(name, _)| name.clone())
+            .collect()
+    }
+}
+
+/// Files and empty directories under `dir` named by their `/` separated path relative to it,
+/// prefixed with `name`. Directory names end with `/` and are matched against include patterns
+/// without it. Symlinks are listed like files when `links` is set, skipped otherwise.
+pub fn dir_entries(
+    dir: &Path,
+    name: &str,
+    filter: &PathFilter,
+    links: bool,
+) -> io::Result<Vec<(PathBuf, String)>> {
+    fn walk(
+        dir: &Path,
+        rel: &str,
+        filter: &PathFilter,
+        links: bool,
+        out: &mut Vec<(PathBuf, String)>,
+    ) -> io::Result<()> {
+        let mut entries = fs::read_dir(dir)?.collect::<Result<Vec<_>, _>>()?;
+        if entries.is_empty() {
+            // The uploaded directory itself is kept whatever the filter.
+            if rel.is_empty() || filter.includes(rel) {
+                out.push((dir.to_owned(), format!("{}/", rel)));
+            }
+            return Ok(());
+        }
+        entries.sort_by_key(|entry| entry.file_name());
+        for entry in entries {
+            let file_name = entry.file_name().into_string().map_err(|name| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("file name {:?} is not valid unicode", name),
+                )
+            })?;
+            let path = if rel.is_empty() {
+                file_name
+            } else {
+                format!("{}/{}", rel, file_name)
+            };
+            if filter.excludes(&path) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                walk(&entry.path(), &path, filter, links, out)?;
+            } else if (file_type.is_file() || (links && file_type.is_symlink()))
+                && filter.includes(&path)
+            {
+                out.push((entry.path(), path));
+            }
+        }
+        Ok(())
+    }
+
+    let mut entries = Vec::new();
+    walk(dir, "", filter, links, &mut entries)?;
+    Ok(entries
+        .into_iter()
+        .filter_map(|(path, rel)| {
+            let file_name = match (name.trim_end_matches('/'), rel.as_str()) {
+                ("", "/") => return None,
+                (name, "/") => format!("{}/", name),
+                ("", rel) => rel.to_owned(),
+                (name, rel) => format!("{}/{}", name, rel),
+            };
+            Some((path, file_name))
+        })
+        .collect())
+}
+
+/// Where a resource file goes under the download directory, `None` if its name would leave
+/// the directory.
+pub fn relative_path(file_name: &str) -> Option<PathBuf> {
+    let mut path = PathBuf::new();
+    for part in file_name.trim_end_matches('/').split('/') {
+        let mut components = Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(part)), None) => path.push(part),
+            _ => return None,
+        }
+    }
+    Some(path)
+}
+
 pub fn hash_bundles(maps: impl IntoIterator<Item = impl Borrow<FileMap>>) -> u128 {
     let mut digest = sha2::Sha224::new();
     for map in maps {
@@ -82,8 +764,782 @@ pub fn hash_bundles(maps: impl IntoIterator<Item = impl Borrow<FileMap>>) -> u12
     extract_results(digest)
 }
 
+/// Blob hash of the tree format. Tagged so it never matches a flat hash of the same files.
+pub fn hash_headers(headers: impl IntoIterator<Item = impl Borrow<FileHeader>>) -> u128 {
+    let mut digest = sha2::Sha224::new();
+    digest.input([MapFormat::Tree as u8]);
+    for header in headers {
+        bincode::serialize_into(&mut digest, header.borrow()).unwrap();
+    }
+    extract_results(digest)
+}
+
+/// Blob hash of the cdc format, commits to chunk offsets as well.
+pub fn hash_chunked<'a>(entries: impl IntoIterator<Item = (&'a FileMap, &'a [u64])>) -> u128 {
+    let mut digest = sha2::Sha224::new();
+    digest.input([MapFormat::Cdc as u8]);
+    for entry in entries {
+        bincode::serialize_into(&mut digest, &entry).unwrap();
+    }
+    extract_results(digest)
+}
+
+/// Tag of blob hashes covering file metadata.
+const META_TAG: u8 = 4;
+
+/// Blob hash of files with metadata, commits to the hash of the files without it.
+pub fn hash_meta<'a>(
+    files_hash: u128,
+    metas: impl IntoIterator<Item = Option<&'a FileMeta>>,
+) -> u128 {
+    let mut digest = sha2::Sha224::new();
+    digest.input([META_TAG]);
+    digest.input(files_hash.to_le_bytes());
+    for meta in metas {
+        bincode::serialize_into(&mut digest, &meta).unwrap();
+    }
+    extract_results(digest)
+}
+
+/// Blob hash of `maps` in `format`. Metadata is covered only if some file has it, so files
+/// shared without metadata hash as they always did.
+pub fn map_hash<'a>(format: MapFormat, maps: impl IntoIterator<Item = &'a FileMap>) -> u128 {
+    let maps: Vec<&FileMap> = maps.into_iter().collect();
+    let files_hash = match format {
+        MapFormat::Flat => hash_bundles(maps.iter().copied()),
+        MapFormat::Tree => hash_headers(maps.iter().map(|map| map.header())),
+        MapFormat::Cdc => hash_chunked(maps.iter().map(|map| (*map, map.chunks.as_slice()))),
+    };
+    if maps.iter().any(|map| map.meta.is_some()) {
+        hash_meta(files_hash, maps.iter().map(|map| map.meta.as_ref()))
+    } else {
+        files_hash
+    }
+}
+
+/// Symlink of `maps` pointing out of the download directory, at a directory holding it, through
+/// another symlink of `maps` or with other files under it.
+pub fn invalid_link<'a>(
+    maps: impl IntoIterator<Item = &'a FileMap> + Clone,
+) -> Option<&'a FileMap> {
+    let links: Vec<(&FileMap, &str)> = maps
+        .clone()
+        .into_iter()
+        .filter_map(|map| Some((map, map.symlink()?)))
+        .collect();
+    let names: HashSet<&str> = links
+        .iter()
+        .map(|(link, _)| link.file_name.as_str())
+        .collect();
+    for &(link, target) in &links {
+        let target_ok = !target.starts_with('/')
+            && match link_target(&link.file_name, target, &names) {
+                Some(path) => {
+                    !path.is_empty() && !link.file_name.starts_with(&format!("{}/", path))
+                }
+                None => false,
+            };
+        let prefix = format!("{}/", link.file_name);
+        if !target_ok
+            || maps
+                .clone()
+                .into_iter()
+                .any(|map| map.file_name.starts_with(&prefix))
+        {
+            return Some(link);
+        }
+    }
+    None
+}
+
+/// Path `target` of the symlink `link` resolves to, `None` if it leaves the top or passes
+/// through one of `links`.
+fn link_target(link: &str, target: &str, links: &HashSet<&str>) -> Option<String> {
+    let mut path: Vec<&str> = link.split('/').collect();
+    path.pop();
+    for part in target.split('/') {
+        match part {
+            "" | "." => (),
+            ".." => {
+                path.pop()?;
+            }
+            part if relative_path(part).is_some() => {
+                path.push(part);
+                if links.contains(path.join("/").as_str()) {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(path.join("/"))
+}
+
+/// File maps of a cdc manifest, `None` if chunk offsets do not fit the files.
+pub fn chunked_maps(entries: Vec<(FileMap, Vec<u64>)>) -> Option<Vec<FileMap>> {
+    entries
+        .into_iter()
+        .map(|(mut map, chunks)| {
+            map.chunks = chunks;
+            // Only empty files have no chunks.
+            if (map.chunks.is_empty() && map.file_size > 0) || !map.chunks_valid() {
+                return None;
+            }
+            Some(map)
+        })
+        .collect()
+}
+
+#[inline]
+pub fn block_count(file_size: u64) -> usize {
+    file_size.div_ceil(BLOCK_SIZE as u64) as usize
+}
+
+pub fn file_root(blocks: &[u128]) -> u128 {
+    if blocks.is_empty() {
+        return merkle::leaf_hash(&[]);
+    }
+    merkle::root(blocks)
+}
+
+/// Proof of block hashes `start..start + count` against the file root.
+pub fn block_hashes_proof(file_map: &FileMap, start: u32, count: u32) -> Option<Vec<u128>> {
+    let end = (start as usize).checked_add(count as usize)?;
+    if count == 0 || end > file_map.blocks.len() {
+        return None;
+    }
+    Some(merkle::range_proof(
+        &file_map.blocks,
+        start as usize,
+        count as usize,
+    ))
+}
+
+pub fn verify_block_hashes(
+    header: &FileHeader,
+    start: u32,
+    hashes: &[u128],
+    proof: &[u128],
+) -> bool {
+    merkle::verify_range(
+        header.root,
+        header.block_count(),
+        start as usize,
+        hashes,
+        proof,
+    )
+}
+
+pub fn chunk_hashes(block: &[u8]) -> Vec<u128> {
+    if block.is_empty() {
+        return vec![merkle::leaf_hash(block)];
+    }
+    block.chunks(CHUNK_SIZE).map(merkle::leaf_hash).collect()
+}
+
+/// Block hash of the flat format.
 pub fn hash_block(block: &[u8]) -> u128 {
     let mut digest = sha2::Sha224::new();
     digest.input(block);
     extract_results(digest)
 }
+
+/// Block hash of the tree and cdc formats.
+pub fn chunk_root(block: &[u8]) -> u128 {
+    merkle::root(&chunk_hashes(block))
+}
+
+/// Whether `block` has hash `block_hash` in any format. Hashes are 128 bits, so a block of one
+/// format can't be passed off as a block of the other.
+pub fn verify_block(block: &[u8], block_hash: u128) -> bool {
+    hash_block(block) == block_hash || chunk_root(block) == block_hash
+}
+
+/// Hash of a block of `size` zero bytes. Chunk roots are computed without hashing the zeros.
+pub fn zero_block_hash(format: MapFormat, size: usize) -> u128 {
+    static ZERO_CHUNK: OnceLock<u128> = OnceLock::new();
+    static FULL_BLOCK: OnceLock<u128> = OnceLock::new();
+    static FULL_FLAT_BLOCK: OnceLock<u128> = OnceLock::new();
+
+    if !format.has_chunk_roots() {
+        let hash = || hash_block(&vec![0; size]);
+        return if size == BLOCK_SIZE {
+            *FULL_FLAT_BLOCK.get_or_init(hash)
+        } else {
+            hash()
+        };
+    }
+    let zero_chunk = *ZERO_CHUNK.get_or_init(|| merkle::leaf_hash(&[0; CHUNK_SIZE]));
+    let hash = || {
+        let mut leaves = vec![zero_chunk; size / CHUNK_SIZE];
+        if size % CHUNK_SIZE != 0 || size == 0 {
+            leaves.push(merkle::leaf_hash(&vec![0; size % CHUNK_SIZE]));
+        }
+        merkle::root(&leaves)
+    };
+    if size == BLOCK_SIZE {
+        *FULL_BLOCK.get_or_init(hash)
+    } else {
+        hash()
+    }
+}
+
+/// Hash of `block`, cheaper for blocks of zeros.
+fn hash_data_block(format: MapFormat, block: &[u8]) -> u128 {
+    if block.iter().all(|b| *b == 0) {
+        zero_block_hash(format, block.len())
+    } else {
+        format.hash_block(block)
+    }
+}
+
+/// Chunks of a single block that cover a byte range of a file.
+pub struct RangeSpan {
+    pub block_nr: u32,
+    pub block_offset: u64,
+    pub block_size: usize,
+    pub first_chunk: usize,
+    pub chunk_count: usize,
+}
+
+impl RangeSpan {
+    /// Returns `None` if the range is empty, crosses a block boundary or exceeds the file.
+    pub fn new(file_map: &FileMap, offset: u64, len: u32) -> Option<Self> {
+        let end = offset.checked_add(len as u64)?;
+        if len == 0 || end > file_map.file_size {
+            return None;
+        }
+        let block_nr = file_map.block_at(offset)?;
+        let (block_offset, block_size) = file_map.block_span(block_nr)?;
+        if end > block_offset + block_size as u64 {
+            return None;
+        }
+        let first_chunk = (offset - block_offset) as usize / CHUNK_SIZE;
+        let last_chunk = (end - 1 - block_offset) as usize / CHUNK_SIZE;
+
+        Some(RangeSpan {
+            block_nr: block_nr as u32,
+            block_offset,
+            block_size,
+            first_chunk,
+            chunk_count: last_chunk - first_chunk + 1,
+        })
+    }
+
+    /// Offset of the first covered chunk within the block.
+    pub fn chunks_offset(&self) -> usize {
+        self.first_chunk * CHUNK_SIZE
+    }
+
+    pub fn chunks_size(&self) -> usize {
+        min(
+            (self.first_chunk + self.chunk_count) * CHUNK_SIZE,
+            self.block_size,
+        ) - self.chunks_offset()
+    }
+
+    pub fn leaf_count(&self) -> usize {
+        self.block_size.div_ceil(CHUNK_SIZE)
+    }
+}
+
+/// Checks chunks received for a byte range against block hash and returns the requested
+/// bytes. Only blocks of tree and cdc maps can be checked in parts.
+pub fn verify_range<'a>(
+    file_map: &FileMap,
+    offset: u64,
+    len: u32,
+    chunks: &'a [u8],
+    proof: &[u128],
+) -> Option<&'a [u8]> {
+    let span = RangeSpan::new(file_map, offset, len)?;
+    if chunks.len() != span.chunks_size() {
+        return None;
+    }
+    let leaves: Vec<u128> = chunks.chunks(CHUNK_SIZE).map(merkle::leaf_hash).collect();
+    let block_hash = *file_map.blocks.get(span.block_nr as usize)?;
+    if !merkle::verify_range(
+        block_hash,
+        span.leaf_count(),
+        span.first_chunk,
+        &leaves,
+        proof,
+    ) {
+        return None;
+    }
+    let start = (offset - span.block_offset) as usize - span.chunks_offset();
+    Some(&chunks[start..start + len as usize])
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn file_map(data: &[u8]) -> FileMap {
+        file_map_as(MapFormat::Flat, data)
+    }
+
+    fn file_map_as(format: MapFormat, data: &[u8]) -> FileMap {
+        FileMap {
+            file_name: "test".into(),
+            file_size: data.len() as u64,
+            blocks: data
+                .chunks(BLOCK_SIZE)
+                .map(|block| format.hash_block(block))
+                .collect(),
+            chunks: Vec::new(),
+            meta: None,
+            inline: None,
+        }
+    }
+
+    #[test]
+    fn test_hash_file() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + CHUNK_SIZE * 3 + 5)
+            .map(|i| (i % 253) as u8)
+            .collect();
+        let path = std::env::temp_dir().join(format!("hyperg-test-hash-{}", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let map = hash_file_as(MapFormat::Flat, &path, "test").unwrap();
+        let tree = hash_file_as(MapFormat::Tree, &path, "test").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(map.file_size, data.len() as u64);
+        assert_eq!(map.blocks, file_map(&data).blocks);
+        assert_eq!(tree.blocks, file_map_as(MapFormat::Tree, &data).blocks);
+        assert_ne!(map.blocks, tree.blocks);
+        for (block, block_hash) in data.chunks(BLOCK_SIZE).zip(&map.blocks) {
+            assert!(verify_block(block, *block_hash));
+        }
+        assert!(verify_block(&data[..BLOCK_SIZE], tree.blocks[0]));
+        assert!(!verify_block(&data[..BLOCK_SIZE], tree.blocks[1]));
+    }
+
+    #[test]
+    fn test_zero_blocks() {
+        for &size in &[0, 1, CHUNK_SIZE, CHUNK_SIZE * 3 + 5, BLOCK_SIZE] {
+            for &format in &[MapFormat::Flat, MapFormat::Tree] {
+                assert_eq!(
+                    zero_block_hash(format, size),
+                    format.hash_block(&vec![0; size]),
+                    "size {} {:?}",
+                    size,
+                    format
+                );
+            }
+        }
+
+        let mut data = vec![0u8; BLOCK_SIZE * 2 + 100];
+        data[BLOCK_SIZE + 7] = 1;
+        let path = std::env::temp_dir().join(format!("hyperg-test-zero-{}", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let map = hash_file_as(MapFormat::Flat, &path, "test").unwrap();
+        let tree = hash_file_as(MapFormat::Tree, &path, "test").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(map.blocks, file_map(&data).blocks);
+        assert_eq!(tree.blocks, file_map_as(MapFormat::Tree, &data).blocks);
+        for map in &[map, tree] {
+            assert!(map.is_zero_block(0));
+            assert!(!map.is_zero_block(1));
+            assert!(map.is_zero_block(2));
+            assert!(!map.is_zero_block(3));
+        }
+    }
+
+    #[test]
+    fn test_list_files() {
+        let dir = std::env::temp_dir().join(format!("hyperg-test-list-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("b.bin"), b"b").unwrap();
+        fs::write(dir.join("sub").join("a.bin"), b"a").unwrap();
+
+        let files = list_files(&dir).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            files,
+            vec![dir.join("b.bin"), dir.join("sub").join("a.bin")]
+        );
+    }
+
+    #[test]
+    fn test_file_selection() {
+        let maps: Vec<FileMap> = ["out/result.bin", "out/log.txt", "input/[1].dat"]
+            .iter()
+            .map(|name| FileMap::empty(*name))
+            .collect();
+        let select = |patterns: &[&str]| {
+            let patterns: Vec<String> = patterns.iter().map(|p| p.to_string()).collect();
+            FileSelection::new(&patterns).unwrap()
+        };
+
+        let all = FileSelection::default();
+        assert!(maps.iter().all(|map| all.includes(&map.file_name)));
+        assert!(all.matched(maps.iter()).is_empty());
+
+        // Exact names need no escaping of glob characters.
+        let selection = select(&["out/*.bin", "input/[1].dat", "missing.txt"]);
+        let picked: Vec<&str> = maps
+            .iter()
+            .filter(|map| selection.includes(&map.file_name))
+            .map(|map| map.file_name.as_str())
+            .collect();
+        assert_eq!(picked, vec!["out/result.bin", "input/[1].dat"]);
+        assert_eq!(
+            selection.matched(maps.iter()),
+            vec!["out/*.bin".to_string(), "input/[1].dat".to_string()]
+        );
+        assert!(FileSelection::new(&["[".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_dir_entries() {
+        let dir = std::env::temp_dir().join(format!("hyperg-test-dir-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub").join("empty")).unwrap();
+        fs::create_dir_all(dir.join("target")).unwrap();
+        fs::write(dir.join("a.txt"), b"a").unwrap();
+        fs::write(dir.join("b.log"), b"b").unwrap();
+        fs::write(dir.join("sub").join("c.txt"), b"c").unwrap();
+        fs::write(dir.join("target").join("d.txt"), b"d").unwrap();
+
+        let filter = PathFilter::new(&["*.txt".to_owned()], &["target".to_owned()]).unwrap();
+        let entries = dir_entries(&dir, "task", &filter, false).unwrap();
+        let dirs = PathFilter::new(&["sub/e*".to_owned()], &[]).unwrap();
+        let with_dirs = dir_entries(&dir, "task", &dirs, false).unwrap();
+        let all = dir_entries(&dir.join("sub"), "", &PathFilter::default(), false).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                (dir.join("a.txt"), "task/a.txt".to_owned()),
+                (dir.join("sub").join("c.txt"), "task/sub/c.txt".to_owned()),
+            ]
+        );
+        assert_eq!(
+            with_dirs,
+            vec![(dir.join("sub").join("empty"), "task/sub/empty/".to_owned())]
+        );
+        assert_eq!(
+            all,
+            vec![
+                (dir.join("sub").join("c.txt"), "c.txt".to_owned()),
+                (dir.join("sub").join("empty"), "empty/".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_relative_path() {
+        assert_eq!(
+            relative_path("task/sub/c.txt"),
+            Some(Path::new("task").join("sub").join("c.txt"))
+        );
+        assert_eq!(relative_path("empty/"), Some(PathBuf::from("empty")));
+        assert_eq!(relative_path("../etc/passwd"), None);
+        assert_eq!(relative_path("/etc/passwd"), None);
+        assert_eq!(relative_path("a//b"), None);
+        assert_eq!(relative_path("a/./b"), None);
+        assert_eq!(relative_path(""), None);
+    }
+
+    #[test]
+    fn test_hash_file_cdc() {
+        let data: Vec<u8> = (0..BLOCK_SIZE * 3 + 5)
+            .map(|i| (((i * 7919) % 251) ^ (i >> 12)) as u8)
+            .collect();
+        let path = std::env::temp_dir().join(format!("hyperg-test-cdc-{}", std::process::id()));
+        fs::write(&path, &data).unwrap();
+        let map = hash_file_as(MapFormat::Cdc, &path, "test").unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(map.file_size, data.len() as u64);
+        assert_eq!(map.chunks.len(), map.blocks.len());
+        assert!(map.chunks_valid());
+        for (block_nr, block_hash) in map.blocks.iter().enumerate() {
+            let (offset, size) = map.block_span(block_nr).unwrap();
+            assert_eq!(
+                chunk_root(&data[offset as usize..offset as usize + size]),
+                *block_hash
+            );
+            assert_eq!(map.block_at(offset), Some(block_nr));
+            assert_eq!(map.block_at(offset + size as u64 - 1), Some(block_nr));
+        }
+        assert_eq!(map.block_at(map.file_size), None);
+
+        let (offset, size) = map.block_span(1).unwrap();
+        let span = RangeSpan::new(&map, offset + 10, 100).unwrap();
+        assert_eq!((span.block_nr, span.block_size), (1, size));
+        assert!(RangeSpan::new(&map, offset - 1, 2).is_none());
+
+        // Manifest commits to chunk offsets.
+        let manifest = Manifest::new(MapFormat::Cdc, Some(&map));
+        assert_eq!(manifest.map_hash(), map_hash(MapFormat::Cdc, Some(&map)));
+        assert_ne!(manifest.map_hash(), map_hash(MapFormat::Flat, Some(&map)));
+        let mut shifted = map.chunks.clone();
+        shifted[1] += 1;
+        assert!(chunked_maps(vec![(map.clone(), map.chunks.clone())]).is_some());
+        assert!(chunked_maps(vec![(map.clone(), shifted)]).is_none());
+        assert!(chunked_maps(vec![(map.clone(), Vec::new())]).is_none());
+    }
+
+    #[test]
+    fn test_tree_manifest() {
+        let maps = vec![
+            FileMap {
+                file_name: "big".into(),
+                file_size: BLOCK_SIZE as u64 * 5 - 1,
+                blocks: (0..5u32)
+                    .map(|i| merkle::leaf_hash(&i.to_le_bytes()))
+                    .collect(),
+                chunks: Vec::new(),
+                meta: None,
+                inline: None,
+            },
+            file_map(&[]),
+        ];
+        let flat = Manifest::new(MapFormat::Flat, &maps);
+        let tree = Manifest::new(MapFormat::Tree, &maps);
+        assert_eq!(flat.map_hash(), hash_bundles(&maps));
+        assert_eq!(tree.map_hash(), map_hash(MapFormat::Tree, &maps));
+        assert_ne!(flat.map_hash(), tree.map_hash());
+        assert_eq!(MapFormat::of(tree.map_hash(), &maps), Some(MapFormat::Tree));
+        assert_eq!(MapFormat::of(flat.map_hash(), &maps), Some(MapFormat::Flat));
+        assert_eq!(MapFormat::of(0, &maps), None);
+
+        let header = maps[0].header();
+        assert_eq!(header.block_count(), 5);
+        let proof = block_hashes_proof(&maps[0], 1, 3).unwrap();
+        assert!(verify_block_hashes(
+            &header,
+            1,
+            &maps[0].blocks[1..4],
+            &proof
+        ));
+        assert!(!verify_block_hashes(
+            &header,
+            2,
+            &maps[0].blocks[1..4],
+            &proof
+        ));
+        assert!(block_hashes_proof(&maps[0], 4, 2).is_none());
+        assert!(block_hashes_proof(&maps[1], 0, 1).is_none());
+        assert_eq!(maps[1].header().block_count(), 0);
+    }
+
+    #[test]
+    fn test_meta_manifest() {
+        let mut maps: Vec<FileMap> = (0..6u32)
+            .map(|i| FileMap {
+                file_name: format!("file-{}", i),
+                file_size: BLOCK_SIZE as u64,
+                blocks: vec![i as u128],
+                chunks: Vec::new(),
+                meta: None,
+                inline: None,
+            })
+            .collect();
+        let plain_hash = map_hash(MapFormat::Flat, &maps);
+        // Only later files have metadata, so early pages come without it.
+        for map in &mut maps[4..] {
+            map.meta = Some(FileMeta {
+                mode: Some(0o755),
+                mtime: Some(1_500_000_000),
+                symlink: None,
+            });
+        }
+        let meta_hash = map_hash(MapFormat::Flat, &maps);
+        assert_ne!(meta_hash, plain_hash);
+        assert_eq!(MapFormat::of(meta_hash, &maps), Some(MapFormat::Flat));
+
+        let whole = Manifest::new(MapFormat::Flat, &maps);
+        assert_eq!(whole.map_hash(), meta_hash);
+        assert_eq!(whole.header().map_hash, meta_hash);
+        assert_eq!(whole.len(), 6);
+
+        let mut paged = Manifest::page(MapFormat::Flat, &maps[..0], 0);
+        while paged.len() < maps.len() {
+            let page = Manifest::page(MapFormat::Flat, &maps[paged.len()..], 0);
+            assert_eq!(
+                matches!(page, Manifest::Meta(..)),
+                paged.len() >= 4,
+                "page at {}",
+                paged.len()
+            );
+            assert!(paged.append(page));
+        }
+        assert_eq!(paged.map_hash(), meta_hash);
+    }
+
+    #[test]
+    fn test_invalid_link() {
+        let link = |name: &str, target: &str| FileMap {
+            meta: Some(FileMeta {
+                symlink: Some(target.to_owned()),
+                ..FileMeta::default()
+            }),
+            ..FileMap::empty(name)
+        };
+        let file = FileMap::empty("task/lib/x.so");
+        let check = |maps: &[FileMap]| invalid_link(maps).map(|map| map.file_name.clone());
+
+        assert_eq!(
+            check(&[file.clone(), link("task/bin/x", "../lib/x.so")]),
+            None
+        );
+        assert_eq!(
+            check(&[link("task/x", "../../etc/passwd")]),
+            Some("task/x".into())
+        );
+        assert_eq!(check(&[link("x", "/etc/passwd")]), Some("x".into()));
+        assert_eq!(check(&[link("x", "a/../..")]), Some("x".into()));
+        assert_eq!(check(&[link("x", "")]), Some("x".into()));
+        // Files are never written through a link.
+        assert_eq!(
+            check(&[link("task/lib", "../bin"), file.clone()]),
+            Some("task/lib".into())
+        );
+        // Links to a directory holding them.
+        assert_eq!(check(&[link("task/lib", ".")]), Some("task/lib".into()));
+        assert_eq!(check(&[link("task/lib", "..")]), Some("task/lib".into()));
+        assert_eq!(
+            check(&[link("task/x/y", "../../task")]),
+            Some("task/x/y".into())
+        );
+        // Paths through another link are not where they seem, `s/..` here is above the top.
+        assert_eq!(
+            check(&[link("s", "."), link("x/l", "../s/..")]),
+            Some("s".into())
+        );
+        assert_eq!(
+            check(&[link("s", "task"), link("x/l", "../s/..")]),
+            Some("x/l".into())
+        );
+        assert_eq!(
+            check(&[link("a", "b"), link("b", "task"), file.clone()]),
+            Some("a".into())
+        );
+        assert_eq!(check(&[link("a", "a")]), Some("a".into()));
+        // Links into the subtree of another link.
+        assert_eq!(
+            check(&[link("task/lib2", "lib"), link("task/x", "lib2/x.so")]),
+            Some("task/x".into())
+        );
+        assert_eq!(
+            check(&[
+                link("task/lib2", "lib"),
+                link("task/x", "../task/lib2/../lib/x.so"),
+                file
+            ]),
+            Some("task/x".into())
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_meta() {
+        let dir = std::env::temp_dir().join(format!("hyperg-test-meta-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("run.sh");
+        fs::write(&path, b"#!/bin/sh").unwrap();
+        let meta = FileMeta {
+            mode: Some(0o4755),
+            mtime: Some(1_500_000_000),
+            symlink: None,
+        };
+        meta.apply(&path).unwrap();
+        let link = dir.join("link");
+        FileMeta::symlink("run.sh", &link).unwrap();
+
+        let read = FileMeta::read(&path).unwrap();
+        let read_link = FileMeta::read(&link).unwrap();
+        let entries = dir_entries(&dir, "", &PathFilter::default(), true).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(
+            read,
+            FileMeta {
+                mode: Some(0o755),
+                ..meta
+            }
+        );
+        assert_eq!(read_link.symlink.as_deref(), Some("run.sh"));
+        assert_eq!(entries.len(), 2);
+    }
+
+    #[test]
+    fn test_manifest_pages() {
+        let maps: Vec<FileMap> = (0..10u32)
+            .map(|i| FileMap {
+                file_name: format!("file-{}", i),
+                file_size: BLOCK_SIZE as u64 * 4,
+                blocks: vec![i as u128; 4],
+                chunks: Vec::new(),
+                meta: None,
+                inline: None,
+            })
+            .collect();
+
+        for &format in &[MapFormat::Flat, MapFormat::Tree] {
+            let whole = Manifest::new(format, &maps);
+            let header = whole.header();
+            assert_eq!(header.file_count, 10);
+            assert_eq!(header.total_size, BLOCK_SIZE as u64 * 40);
+
+            let entry_size = bincode::serialized_size(&Manifest::new(format, &maps[..1])).unwrap();
+            let mut paged = Manifest::page(format, &maps[..0], 0);
+            while paged.len() < maps.len() {
+                let page = Manifest::page(format, &maps[paged.len()..], entry_size * 3);
+                assert_eq!(page.len(), min(3, maps.len() - paged.len()));
+                assert!(paged.append(page));
+            }
+            assert_eq!(paged.header(), header);
+            assert_eq!(Manifest::page(format, &maps, 0).len(), 1);
+        }
+        assert!(
+            !Manifest::new(MapFormat::Flat, &maps).append(Manifest::new(MapFormat::Tree, &maps))
+        );
+    }
+
+    #[test]
+    fn test_verify_range() {
+        let data: Vec<u8> = (0..BLOCK_SIZE + CHUNK_SIZE * 3 + 5)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let map = file_map_as(MapFormat::Tree, &data);
+
+        for &(offset, len) in &[
+            (0u64, 10u32),
+            (CHUNK_SIZE as u64 - 1, 2),
+            (BLOCK_SIZE as u64 - 3, 3),
+            (
+                BLOCK_SIZE as u64 + CHUNK_SIZE as u64,
+                (CHUNK_SIZE * 2 + 5) as u32,
+            ),
+        ] {
+            let span = RangeSpan::new(&map, offset, len).unwrap();
+            let block_offset = span.block_nr as usize * BLOCK_SIZE;
+            let block = &data[block_offset..block_offset + span.block_size];
+            let proof =
+                merkle::range_proof(&chunk_hashes(block), span.first_chunk, span.chunk_count);
+            let chunks = &block[span.chunks_offset()..span.chunks_offset() + span.chunks_size()];
+
+            let range = verify_range(&map, offset, len, chunks, &proof).unwrap();
+            assert_eq!(
+                range,
+                &data[offset as usize..offset as usize + len as usize]
+            );
+
+            let mut tampered = chunks.to_vec();
+            tampered[0] ^= 1;
+            assert!(verify_range(&map, offset, len, &tampered, &proof).is_none());
+            // Flat block hashes can't be proven in parts.
+            assert!(verify_range(&file_map(&data), offset, len, chunks, &proof).is_none());
+        }
+
+        assert!(RangeSpan::new(&map, BLOCK_SIZE as u64 - 1, 2).is_none());
+        assert!(RangeSpan::new(&map, 0, 0).is_none());
+        assert!(RangeSpan::new(&map, data.len() as u64, 1).is_none());
+    }
+}
@@ -0,0 +1,276 @@
+//! Per-connection flow control. Outbound bytes are counted between encoder and socket, reading
+//! from the peer stops while too much data waits to be sent or too many requests are in
+//! progress.
+
+use bytes::BytesMut;
+use futures::prelude::*;
+use futures::task::{self, Task};
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use tokio_io::codec::Encoder;
+use tokio_io::AsyncWrite;
+
+/// Reading stops when more bytes than this wait in the outbound buffer.
+pub const HIGH_WATERMARK: u64 = 1024 * 1024 * 16;
+
+/// Reading resumes once the outbound buffer drains below this.
+pub const LOW_WATERMARK: u64 = 1024 * 1024 * 4;
+
+/// Maximum number of peer requests served at once.
+pub const MAX_OUTSTANDING_REQUESTS: usize = 64;
+
+#[derive(Default)]
+struct State {
+    encoded: u64,
+    written: u64,
+    outstanding: usize,
+    paused: bool,
+    resume_pending: bool,
+    reader: Option<Task>,
+    resumed: Option<Task>,
+}
+
+impl State {
+    fn queued(&self) -> u64 {
+        self.encoded - self.written
+    }
+
+    fn release(&mut self) {
+        if self.paused
+            && self.queued() <= LOW_WATERMARK
+            && self.outstanding < MAX_OUTSTANDING_REQUESTS
+        {
+            self.paused = false;
+            self.resume_pending = true;
+            for task in self.reader.take().into_iter().chain(self.resumed.take()) {
+                task.notify();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Meter(Rc<RefCell<State>>);
+
+impl Meter {
+    pub fn new() -> Self {
+        Meter::default()
+    }
+
+    /// Bytes encoded but not yet accepted by the socket.
+    pub fn queued(&self) -> u64 {
+        self.0.borrow().queued()
+    }
+
+    pub fn is_congested(&self) -> bool {
+        let state = self.0.borrow();
+        state.queued() > HIGH_WATERMARK || state.outstanding >= MAX_OUTSTANDING_REQUESTS
+    }
+
+    /// Peer requests being served.
+    pub fn outstanding(&self) -> usize {
+        self.0.borrow().outstanding
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.borrow().paused
+    }
+
+    /// Stops reading until congestion clears.
+    pub fn pause(&self) {
+        self.0.borrow_mut().paused = true;
+    }
+
+    pub fn request_started(&self) {
+        self.0.borrow_mut().outstanding += 1;
+    }
+
+    pub fn request_done(&self) {
+        let mut state = self.0.borrow_mut();
+        state.outstanding -= 1;
+        state.release();
+    }
+
+    pub fn codec<C>(&self, codec: C) -> MeteredCodec<C> {
+        MeteredCodec {
+            inner: codec,
+            meter: self.clone(),
+        }
+    }
+
+    pub fn writer<W>(&self, io: W) -> MeteredWrite<W> {
+        MeteredWrite {
+            inner: io,
+            meter: self.clone(),
+        }
+    }
+
+    pub fn gate<S>(&self, stream: S) -> Gate<S> {
+        Gate {
+            inner: stream,
+            meter: self.clone(),
+        }
+    }
+
+    /// Yields every time reading resumes after a pause.
+    pub fn resumed(&self) -> Resumed {
+        Resumed(self.clone())
+    }
+}
+
+pub struct MeteredCodec<C> {
+    inner: C,
+    meter: Meter,
+}
+
+impl<C: Encoder> Encoder for MeteredCodec<C> {
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let before = dst.len();
+        let result = self.inner.encode(item, dst);
+        self.meter.0.borrow_mut().encoded += (dst.len() - before) as u64;
+        result
+    }
+}
+
+pub struct MeteredWrite<W> {
+    inner: W,
+    meter: Meter,
+}
+
+impl<W: Write> Write for MeteredWrite<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        let mut state = self.meter.0.borrow_mut();
+        state.written += n as u64;
+        state.release();
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: AsyncWrite> AsyncWrite for MeteredWrite<W> {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        self.inner.shutdown()
+    }
+}
+
+/// Inbound stream that is not polled while the meter is paused.
+pub struct Gate<S> {
+    inner: S,
+    meter: Meter,
+}
+
+impl<S: Stream> Stream for Gate<S> {
+    type Item = S::Item;
+    type Error = S::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        {
+            let mut state = self.meter.0.borrow_mut();
+            if state.paused {
+                state.reader = Some(task::current());
+                return Ok(Async::NotReady);
+            }
+        }
+        self.inner.poll()
+    }
+}
+
+pub struct Resumed(Meter);
+
+impl Stream for Resumed {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<()>, ()> {
+        let mut state = (self.0).0.borrow_mut();
+        if state.resume_pending {
+            state.resume_pending = false;
+            Ok(Async::Ready(Some(())))
+        } else {
+            state.resumed = Some(task::current());
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::{future, stream};
+
+    /// Accepts at most `limit` bytes per write.
+    struct Slow {
+        limit: usize,
+        data: Vec<u8>,
+    }
+
+    impl Write for Slow {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = std::cmp::min(buf.len(), self.limit);
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        future::lazy(|| {
+            let meter = Meter::new();
+            let mut codec = meter.codec(crate::codec::StCodec::default());
+            let mut writer = meter.writer(Slow {
+                limit: LOW_WATERMARK as usize,
+                data: Vec::new(),
+            });
+            let mut gate = meter.gate(stream::iter_ok::<_, ()>(vec![1, 2]));
+            let mut resumed = meter.resumed();
+
+            let mut buf = BytesMut::new();
+            for _ in 0..5 {
+                let block = crate::codec::StCommand::block(0, 0, 0, vec![0; 4 * 1024 * 1024]);
+                codec.encode(block, &mut buf).unwrap();
+            }
+            assert_eq!(meter.queued(), buf.len() as u64);
+            assert!(meter.is_congested());
+            meter.pause();
+            assert_eq!(gate.poll(), Ok(Async::NotReady));
+            assert_eq!(resumed.poll(), Ok(Async::NotReady));
+
+            while meter.is_paused() {
+                let n = writer.write(&buf).unwrap();
+                let _ = buf.split_to(n);
+            }
+            assert!(meter.queued() <= LOW_WATERMARK);
+            assert_eq!(resumed.poll(), Ok(Async::Ready(Some(()))));
+            assert_eq!(resumed.poll(), Ok(Async::NotReady));
+            assert_eq!(gate.poll(), Ok(Async::Ready(Some(1))));
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_outstanding_requests() {
+        let meter = Meter::new();
+        for _ in 0..MAX_OUTSTANDING_REQUESTS {
+            assert!(!meter.is_congested());
+            meter.request_started();
+        }
+        assert!(meter.is_congested());
+        meter.pause();
+        meter.request_done();
+        assert!(!meter.is_paused());
+    }
+}
@@ -0,0 +1,168 @@
+use crate::error::Error;
+use ring::rand::SystemRandom;
+use ring::signature::{self, Ed25519KeyPair, KeyPair};
+use sha2::digest::Digest;
+use std::convert::TryInto;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+/// Prefix of every signed handshake challenge.
+const CHALLENGE_CONTEXT: &[u8] = b"hyperg-hello-v3";
+
+/// Size of random challenge sent in handshake.
+pub const CHALLENGE_SIZE: usize = 32;
+
+/// Persistent ed25519 key pair of this node.
+pub struct Identity {
+    key_pair: Ed25519KeyPair,
+    node_id: u128,
+}
+
+impl Identity {
+    /// Loads PKCS#8 encoded key from `path`, or generates and stores a new one.
+    pub fn load_or_create(path: &Path) -> Result<Self, Error> {
+        let pkcs8 = if path.exists() {
+            fs::read(path)?
+        } else {
+            let document = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+                .map_err(|_| Error::InvalidNodeKey)?;
+            let mut options = fs::OpenOptions::new();
+            options.write(true).create_new(true);
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::OpenOptionsExt;
+                options.mode(0o600);
+            }
+            options.open(path)?.write_all(document.as_ref())?;
+            log::info!("generated new node key in {}", path.display());
+            document.as_ref().to_vec()
+        };
+        let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|_| Error::InvalidNodeKey)?;
+        let node_id = node_id(key_pair.public_key().as_ref());
+
+        Ok(Identity { key_pair, node_id })
+    }
+
+    #[inline]
+    pub fn node_id(&self) -> u128 {
+        self.node_id
+    }
+
+    #[inline]
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        self.public_key()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    /// Signs the peer `challenge` bound to the Noise handshake hash, empty on plaintext
+    /// connections.
+    pub fn sign_challenge(&self, challenge: &[u8], session: &[u8]) -> Vec<u8> {
+        self.key_pair
+            .sign(&challenge_message(challenge, session))
+            .as_ref()
+            .to_vec()
+    }
+}
+
+fn challenge_message(challenge: &[u8], session: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(CHALLENGE_CONTEXT.len() + challenge.len() + session.len());
+    message.extend_from_slice(CHALLENGE_CONTEXT);
+    message.extend_from_slice(challenge);
+    message.extend_from_slice(session);
+    message
+}
+
+/// Node id is derived from the public key the same way block hashes are derived from data.
+pub fn node_id(public_key: &[u8]) -> u128 {
+    let mut digest = sha2::Sha224::new();
+    digest.input(public_key);
+    u128::from_le_bytes(digest.result()[0..16].try_into().unwrap())
+}
+
+/// Checks that `signature` was made by owner of `public_key` for our `challenge` within this
+/// Noise session.
+pub fn verify_challenge(
+    public_key: &[u8],
+    challenge: &[u8],
+    session: &[u8],
+    signature: &[u8],
+) -> bool {
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(&challenge_message(challenge, session), signature)
+        .is_ok()
+}
+
+pub fn new_challenge() -> [u8; CHALLENGE_SIZE] {
+    use rand::Rng;
+    rand::thread_rng().gen()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_key_path(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("hyperg-test-{}-{}.key", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_key_persistence() {
+        let path = temp_key_path("persist");
+        let first = Identity::load_or_create(&path).unwrap();
+        let second = Identity::load_or_create(&path).unwrap();
+        assert_eq!(first.node_id(), second.node_id());
+        assert_eq!(first.public_key(), second.public_key());
+        assert_eq!(first.node_id(), node_id(first.public_key()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_challenge() {
+        let path = temp_key_path("challenge");
+        let identity = Identity::load_or_create(&path).unwrap();
+        let challenge = new_challenge();
+        let signature = identity.sign_challenge(&challenge, &[1; 32]);
+
+        assert!(verify_challenge(
+            identity.public_key(),
+            &challenge,
+            &[1; 32],
+            &signature
+        ));
+        assert!(!verify_challenge(
+            identity.public_key(),
+            &new_challenge(),
+            &[1; 32],
+            &signature
+        ));
+        // Signed for another Noise session.
+        assert!(!verify_challenge(
+            identity.public_key(),
+            &challenge,
+            &[2; 32],
+            &signature
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let path = temp_key_path("invalid");
+        fs::write(&path, b"not a key").unwrap();
+        match Identity::load_or_create(&path) {
+            Err(Error::InvalidNodeKey) => (),
+            _ => panic!("invalid key accepted"),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+}
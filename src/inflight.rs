@@ -0,0 +1,111 @@
+//! Downloads in progress, keyed by resource hash. A download of a resource that is already being
+//! transferred waits for that transfer and takes a copy of its files instead of fetching them again.
+
+use crate::filemap::FileMap;
+use futures::sync::oneshot;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+/// Destination directory of a finished transfer and the files written there.
+pub type Files = (PathBuf, Vec<(FileMap, PathBuf)>);
+
+type Waiter = oneshot::Sender<Files>;
+
+#[derive(Default)]
+struct InFlight(HashMap<u128, Vec<Waiter>>);
+
+impl InFlight {
+    /// `None` when there is no transfer of `hash` yet and the caller has to run it.
+    fn join(&mut self, hash: u128) -> Option<oneshot::Receiver<Files>> {
+        match self.0.get_mut(&hash) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Some(rx)
+            }
+            None => {
+                self.0.insert(hash, Vec::new());
+                None
+            }
+        }
+    }
+
+    fn finish(&mut self, hash: u128) -> Vec<Waiter> {
+        self.0.remove(&hash).unwrap_or_default()
+    }
+}
+
+fn in_flight() -> &'static Mutex<InFlight> {
+    static IN_FLIGHT: OnceLock<Mutex<InFlight>> = OnceLock::new();
+    IN_FLIGHT.get_or_init(Default::default)
+}
+
+/// Transfer run by the first download of a resource. Dropped without `finish` (failed or
+/// canceled) it cancels the waiting downloads, which then run transfers of their own.
+pub struct Transfer {
+    hash: u128,
+    finished: bool,
+}
+
+impl Transfer {
+    /// Hands `files` downloaded to `dest` to the waiting downloads.
+    pub fn finish(mut self, dest: &Path, files: &[(FileMap, PathBuf)]) {
+        for waiter in in_flight().lock().unwrap().finish(self.hash) {
+            let _ = waiter.send((dest.to_owned(), files.to_vec()));
+        }
+        self.finished = true;
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if !self.finished {
+            in_flight().lock().unwrap().finish(self.hash);
+        }
+    }
+}
+
+pub enum Join {
+    Transfer(Transfer),
+    /// Resolves to the files of the transfer in progress.
+    Wait(oneshot::Receiver<Files>),
+}
+
+pub fn join(hash: u128) -> Join {
+    match in_flight().lock().unwrap().join(hash) {
+        Some(rx) => Join::Wait(rx),
+        None => Join::Transfer(Transfer {
+            hash,
+            finished: false,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::Future;
+
+    #[test]
+    fn test_in_flight() {
+        let mut in_flight = InFlight::default();
+        assert!(in_flight.join(1).is_none());
+        let a = in_flight.join(1).unwrap();
+        let b = in_flight.join(1).unwrap();
+        assert!(in_flight.join(2).is_none());
+
+        let dest = PathBuf::from("/tmp");
+        for waiter in in_flight.finish(1) {
+            assert!(waiter.send((dest.clone(), Vec::new())).is_ok());
+        }
+        assert_eq!(a.wait().unwrap().0, dest);
+        assert_eq!(b.wait().unwrap().0, dest);
+
+        // Failed transfer cancels the waiting downloads.
+        let c = in_flight.join(2).unwrap();
+        in_flight.finish(2);
+        assert!(c.wait().is_err());
+        assert!(in_flight.join(1).is_none());
+    }
+}
@@ -0,0 +1,148 @@
+//! Keepalive pings and idle timeout of peer connections. Both sides send `nop` every interval, a
+//! peer silent for longer than the peer timeout is considered dead.
+
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Config {
+    /// Ping interval, `None` disables pings and dead peer detection.
+    pub interval: Option<Duration>,
+    pub peer_timeout: Duration,
+    /// Connections without transfers for this long are closed.
+    pub idle_timeout: Option<Duration>,
+    /// Free pooled connections are closed after this long.
+    pub pool_idle: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            interval: Some(Duration::from_secs(15)),
+            peer_timeout: Duration::from_secs(45),
+            idle_timeout: Some(Duration::from_secs(300)),
+            pool_idle: Duration::from_secs(60),
+        }
+    }
+}
+
+impl Config {
+    /// How often connections are checked, `None` when there is nothing to check.
+    pub fn tick(&self) -> Option<Duration> {
+        self.interval.or(self.idle_timeout)
+    }
+}
+
+fn config_cell() -> &'static Mutex<Config> {
+    static CONFIG: OnceLock<Mutex<Config>> = OnceLock::new();
+    CONFIG.get_or_init(Default::default)
+}
+
+pub fn config() -> Config {
+    *config_cell().lock().unwrap()
+}
+
+/// Applies to connections opened later.
+pub fn set_config(config: Config) {
+    log::info!("keepalive: {:?}", config);
+    *config_cell().lock().unwrap() = config;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Verdict {
+    Alive,
+    Dead,
+    Idle,
+}
+
+pub struct Liveness {
+    config: Config,
+    last_received: Instant,
+    last_active: Instant,
+}
+
+impl Liveness {
+    pub fn new(config: Config, now: Instant) -> Self {
+        Liveness {
+            config,
+            last_received: now,
+            last_active: now,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Any packet arrived from the peer.
+    pub fn received(&mut self, now: Instant) {
+        self.last_received = now;
+    }
+
+    /// A transfer made progress.
+    pub fn active(&mut self, now: Instant) {
+        self.last_active = now;
+    }
+
+    /// `busy` tells whether transfers are in progress, `reading` whether packets from the peer
+    /// are being read at all.
+    pub fn check(&mut self, now: Instant, busy: bool, reading: bool) -> Verdict {
+        if !reading {
+            self.last_received = now;
+        }
+        if busy {
+            self.last_active = now;
+        }
+        if self.config.interval.is_some()
+            && now.saturating_duration_since(self.last_received) >= self.config.peer_timeout
+        {
+            return Verdict::Dead;
+        }
+        match self.config.idle_timeout {
+            Some(timeout) if now.saturating_duration_since(self.last_active) >= timeout => {
+                Verdict::Idle
+            }
+            _ => Verdict::Alive,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_liveness() {
+        let now = Instant::now();
+        let secs = |n| now + Duration::from_secs(n);
+        let mut liveness = Liveness::new(Config::default(), now);
+
+        assert_eq!(liveness.check(secs(30), false, true), Verdict::Alive);
+        liveness.received(secs(30));
+        assert_eq!(liveness.check(secs(74), false, true), Verdict::Alive);
+        assert_eq!(liveness.check(secs(75), true, true), Verdict::Dead);
+
+        // Silence while reading is paused does not count.
+        assert_eq!(liveness.check(secs(100), true, false), Verdict::Alive);
+        liveness.received(secs(140));
+        assert_eq!(liveness.check(secs(140), false, true), Verdict::Alive);
+
+        // Nops keep the connection alive, but not busy.
+        for t in (150..400).step_by(15) {
+            liveness.received(secs(t));
+        }
+        assert_eq!(liveness.check(secs(399), false, true), Verdict::Alive);
+        assert_eq!(liveness.check(secs(400), false, true), Verdict::Idle);
+
+        let mut disabled = Liveness::new(
+            Config {
+                interval: None,
+                idle_timeout: None,
+                ..Config::default()
+            },
+            now,
+        );
+        assert_eq!(disabled.check(secs(3600), false, true), Verdict::Alive);
+        assert_eq!(disabled.config().tick(), None);
+    }
+}
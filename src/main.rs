@@ -1,23 +1,28 @@
-use crate::codec::{hash_to_hex, Block, GetBlock};
+use crate::codec::{hash_to_hex, Block, GetBlock, GetBlocks, GetRange, MAX_BATCH_BLOCKS};
 use crate::command::{DownloadResult, PeerInfo, UploadResult};
-use crate::database::{DatabaseManager, RegisterHash};
+use crate::database::{BlockLocation, DatabaseManager, FindBlocks, RegisterHash};
 use crate::download::find_peer;
-use crate::filemap::{hash_block, FileMap};
+use crate::filemap::FileMap;
 use actix::Addr;
 use actix_web::middleware::Logger;
-use actix_web::{delete, get, post, web, App, HttpResponse, HttpServer};
+use actix_web::{delete, get, post, put, web, App, HttpResponse, HttpServer};
 use futures::{future, prelude::*};
+use serde::{Deserialize, Serialize};
 
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::convert::TryFrom;
 use std::fs;
-use std::io::Write;
+use std::io::{Read, Seek, SeekFrom, Write};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
+mod admission;
+mod cdc;
 mod codec;
 mod command;
 mod connection;
@@ -25,8 +30,18 @@ pub(crate) mod database;
 mod download;
 pub(crate) mod error;
 pub(crate) mod filemap;
+mod flow;
+mod identity;
+mod inflight;
+mod keepalive;
 mod log_config;
+mod merkle;
+mod pool;
+mod ratelimit;
+mod reader;
+mod reputation;
 mod server;
+mod transport;
 mod user_report;
 mod version;
 
@@ -57,6 +72,74 @@ struct ServerOpts {
     #[structopt(long, default_value = "3292")]
     rpc_port: u16,
 
+    /// Encryption of peer connections: off, preferred or required
+    #[structopt(long, default_value = "preferred")]
+    encryption: transport::EncryptionMode,
+
+    /// Upload limit for all peers together in bytes per second
+    #[structopt(long)]
+    upload_limit: Option<u64>,
+
+    /// Upload limit for a single peer address in bytes per second
+    #[structopt(long)]
+    peer_upload_limit: Option<u64>,
+
+    /// Upload limit for a single resource in bytes per second
+    #[structopt(long)]
+    resource_upload_limit: Option<u64>,
+
+    /// Download limit for all downloads together in bytes per second
+    #[structopt(long)]
+    download_limit: Option<u64>,
+
+    /// JSON file with time of day windows overriding limits
+    #[structopt(long)]
+    schedule: Option<PathBuf>,
+
+    /// Maximum number of inbound peer connections
+    #[structopt(long)]
+    max_connections: Option<usize>,
+
+    /// Maximum number of inbound peer connections from a single IP address
+    #[structopt(long)]
+    max_connections_per_ip: Option<usize>,
+
+    /// Maximum number of inbound peer connections before the peer is authenticated
+    #[structopt(long)]
+    max_pending_connections: Option<usize>,
+
+    /// Keepalive ping interval in seconds, 0 disables pings and dead peer detection
+    #[structopt(long, default_value = "15")]
+    keepalive_interval: u64,
+
+    /// Seconds without any packet from a peer before its connection is closed
+    #[structopt(long, default_value = "45")]
+    peer_timeout: u64,
+
+    /// Seconds without transfers before a connection is closed, 0 keeps idle connections
+    #[structopt(long, default_value = "300")]
+    idle_timeout: u64,
+
+    /// Seconds a free connection is kept for reuse by later downloads
+    #[structopt(long, default_value = "60")]
+    pool_idle: u64,
+
+    /// Share downloaded resources, unless a download says otherwise
+    #[structopt(long)]
+    seed: bool,
+
+    /// How long downloaded resources are shared, in seconds
+    #[structopt(long, default_value = "259200")]
+    seed_lifetime: u64,
+
+    /// Share file modes, modification times and symlinks on upload and restore them on download
+    #[structopt(long)]
+    metadata: bool,
+
+    /// Files smaller than this many bytes are sent along with the manifest
+    #[structopt(long, default_value = "200")]
+    inline_threshold: u64,
+
     /// Database sweep interval in seconds
     #[structopt(long, default_value = "86400")]
     sweep_interval: u32,
@@ -78,9 +161,465 @@ struct ServerOpts {
     version: bool,
 }
 
+/// Options of a single download.
+#[derive(Clone, Default)]
+struct DownloadOpts {
+    /// Download rate in bytes per second.
+    limit: Option<u64>,
+    /// How long downloaded files are shared.
+    seed: Option<Duration>,
+    basis: Option<command::Basis>,
+    /// Restore modes, modification times and symlinks of files shared with metadata.
+    metadata: bool,
+    /// Files to download, all by default.
+    select: filemap::FileSelection,
+}
+
 struct State {
     db: Addr<DatabaseManager>,
     opts: Arc<ServerOpts>,
+    transport: Arc<transport::TransportConfig>,
+}
+
+fn parse_peers(peers: Vec<PeerInfo>) -> Result<Vec<SocketAddr>, std::net::AddrParseError> {
+    let peers: HashSet<_> = peers
+        .into_iter()
+        .map(|peer_info| match peer_info {
+            PeerInfo::TCP(address, port) => Ok(SocketAddr::new(address.parse()?, port)),
+        })
+        .collect::<Result<_, std::net::AddrParseError>>()?;
+    Ok(peers.into_iter().collect())
+}
+
+/// Keeps contents of single block files smaller than `threshold` bytes in memory, they are
+/// sent along with the manifest. Files changed since hashing are left out.
+fn inline_small_files(file_maps: &mut [(FileMap, PathBuf)], threshold: u64) -> std::io::Result<()> {
+    for (file_map, path) in file_maps {
+        if file_map.inline.is_some()
+            || file_map.file_size >= threshold
+            || file_map.blocks.len() != 1
+        {
+            continue;
+        }
+        let data = fs::read(path)?;
+        if filemap::verify_block(&data, file_map.blocks[0]) {
+            file_map.inline = Some(data);
+        }
+    }
+    Ok(())
+}
+
+/// Blocks of the files `basis` points to. Files of a directory are split the way `format`
+/// expects.
+fn basis_blocks(
+    db: &Addr<DatabaseManager>,
+    basis: Option<command::Basis>,
+    format: filemap::MapFormat,
+) -> Box<dyn Future<Item = HashMap<u128, BlockLocation>, Error = crate::error::Error>> {
+    match basis {
+        None => Box::new(future::ok(HashMap::new())),
+        Some(command::Basis::Dir(dir)) => Box::new(
+            reader::run(move || {
+                filemap::list_files(&dir)?
+                    .into_iter()
+                    .map(|path| Ok((filemap::hash_file_as(format, &path, "")?, path)))
+                    .collect::<Result<Vec<_>, std::io::Error>>()
+            })
+            .map(|files| database::block_locations(&files).collect()),
+        ),
+        Some(command::Basis::Hash(hash)) => {
+            let hash = match u128::from_str_radix(&hash, 16) {
+                Ok(hash) => hash,
+                Err(e) => return Box::new(future::err(e.into())),
+            };
+            Box::new(
+                db.send(database::GetHash(hash))
+                    .flatten()
+                    .and_then(move |r| r.ok_or(crate::error::Error::ResourceNotFound(hash)))
+                    .map(|(desc, _)| database::block_locations(&desc.files).collect()),
+            )
+        }
+    }
+}
+
+/// Reads a local copy of the block when it is still intact, otherwise fetches it from the peer.
+fn get_block(
+    connection: Addr<connection::Connection>,
+    request: GetBlock,
+    block_hash: u128,
+    local: Option<BlockLocation>,
+    bucket: Option<Arc<Mutex<ratelimit::TokenBucket>>>,
+    bytes_saved: Arc<AtomicU64>,
+    reporter: user_report::UserReportHandle,
+) -> impl Future<Item = Block, Error = crate::error::Error> {
+    let GetBlock {
+        hash,
+        file_nr,
+        block_nr,
+    } = request;
+    let fetch = move || {
+        connection
+            .send(request)
+            // min 110Kb/s
+            .timeout(Duration::from_secs(300))
+            .flatten()
+            .and_then(move |b| {
+                if filemap::verify_block(&b.bytes, block_hash) {
+                    Ok(b)
+                } else {
+                    Err(crate::error::Error::InvalidBlockHash(block_hash))
+                }
+            })
+            .and_then(move |b| {
+                ratelimit::throttle_download(b.bytes.len() as u64, bucket.as_deref())
+                    .map(move |()| b)
+            })
+    };
+
+    match local {
+        None => future::Either::A(fetch()),
+        Some(location) => {
+            future::Either::B(reader::run(move || location.read()).then(move |r| match r {
+                Ok(bytes) if filemap::verify_block(&bytes, block_hash) => {
+                    reporter.add_note(|| format!("block block_no:{} found locally", block_nr));
+                    bytes_saved.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                    future::Either::A(future::ok(Block {
+                        hash,
+                        file_nr,
+                        block_nr,
+                        bytes,
+                    }))
+                }
+                // Local file changed since it was shared.
+                _ => future::Either::B(fetch()),
+            }))
+        }
+    }
+}
+
+/// Fetches `len` bytes at `offset` as chunks of a single block, checked with their proof.
+fn get_range(
+    connection: Addr<connection::Connection>,
+    hash: u128,
+    file_nr: u32,
+    file_map: Arc<FileMap>,
+    offset: u64,
+    len: u32,
+) -> impl Future<Item = Vec<u8>, Error = crate::error::Error> {
+    connection
+        .send(GetRange {
+            hash,
+            file_nr,
+            offset,
+            len,
+        })
+        .timeout(Duration::from_secs(300))
+        .flatten()
+        .and_then(move |range| {
+            filemap::verify_range(&file_map, offset, len, &range.chunks, &range.proof)
+                .map(|bytes| bytes.to_vec())
+                .ok_or(crate::error::Error::InvalidRangeProof(offset))
+        })
+}
+
+/// Like `get_range` for blocks that can only be checked whole, fetches the block holding the
+/// range.
+fn get_block_range(
+    connection: Addr<connection::Connection>,
+    hash: u128,
+    file_nr: u32,
+    file_map: Arc<FileMap>,
+    offset: u64,
+    len: u32,
+) -> impl Future<Item = Vec<u8>, Error = crate::error::Error> {
+    let block_nr = file_map.block_at(offset).unwrap_or_default();
+    connection
+        .send(GetBlock {
+            hash,
+            file_nr,
+            block_nr: block_nr as u32,
+        })
+        .timeout(Duration::from_secs(300))
+        .flatten()
+        .and_then(move |b| {
+            let block_hash = file_map.blocks[block_nr];
+            let (block_offset, _) = file_map.block_span(block_nr).unwrap_or_default();
+            let start = (offset - block_offset) as usize;
+            if !filemap::verify_block(&b.bytes, block_hash) {
+                return Err(crate::error::Error::InvalidBlockHash(block_hash));
+            }
+            b.bytes
+                .get(start..start + len as usize)
+                .map(|bytes| bytes.to_vec())
+                .ok_or(crate::error::Error::InvalidBlockHash(block_hash))
+        })
+}
+
+/// Files up to this size have their only block fetched in batches with other small files.
+const SMALL_FILE_SIZE: u64 = 64 * 1024;
+
+/// Blocks of small files, fetched with `GetBlocks` ahead of writing them.
+#[derive(Default)]
+struct SmallBlocks {
+    /// Block hash and size of small files not requested yet, by file number.
+    waiting: BTreeMap<u32, (u128, u64)>,
+    /// Fetched with an earlier file, not written yet.
+    fetched: HashMap<u32, Block>,
+}
+
+impl SmallBlocks {
+    /// Small files among `file_maps`, numbered as in the resource. Those found locally are left
+    /// to `get_block`.
+    fn new(file_maps: &[(usize, FileMap)], local: &HashMap<u128, BlockLocation>) -> Self {
+        let waiting = file_maps
+            .iter()
+            .filter(|(_, file_map)| {
+                file_map.blocks.len() == 1
+                    && file_map.file_size <= SMALL_FILE_SIZE
+                    && file_map.inline.is_none()
+                    && file_map.symlink().is_none()
+                    && !file_map.is_zero_block(0)
+                    && !local.contains_key(&file_map.blocks[0])
+            })
+            .map(|(file_nr, file_map)| (*file_nr as u32, (file_map.blocks[0], file_map.file_size)))
+            .collect();
+        SmallBlocks {
+            waiting,
+            fetched: HashMap::new(),
+        }
+    }
+
+    fn contains(&self, file_nr: u32) -> bool {
+        self.waiting.contains_key(&file_nr) || self.fetched.contains_key(&file_nr)
+    }
+
+    /// Takes `file_nr` with the small files following it, as many as fit in one request and
+    /// `BLOCK_SIZE` bytes.
+    fn next_batch(&mut self, file_nr: u32) -> Vec<(u32, u128)> {
+        let mut total = 0;
+        let batch: Vec<_> = self
+            .waiting
+            .range(file_nr..)
+            .take(MAX_BATCH_BLOCKS)
+            .take_while(|(_, (_, size))| {
+                total += size;
+                total <= filemap::BLOCK_SIZE as u64
+            })
+            .map(|(&file_nr, &(block_hash, _))| (file_nr, block_hash))
+            .collect();
+        for (file_nr, _) in &batch {
+            self.waiting.remove(file_nr);
+        }
+        batch
+    }
+}
+
+/// Returns the block of small file `file_nr`. Unless fetched already, it is requested together
+/// with blocks of the next small files.
+fn get_small_block(
+    connection: Addr<connection::Connection>,
+    hash: u128,
+    file_nr: u32,
+    small: Arc<Mutex<SmallBlocks>>,
+    bucket: Option<Arc<Mutex<ratelimit::TokenBucket>>>,
+) -> impl Future<Item = Block, Error = crate::error::Error> {
+    let batch = {
+        let mut small = small.lock().unwrap();
+        if let Some(block) = small.fetched.remove(&file_nr) {
+            return future::Either::B(future::ok(block));
+        }
+        small.next_batch(file_nr)
+    };
+    let request = GetBlocks {
+        hash,
+        blocks: batch.iter().map(|&(file_nr, _)| (file_nr, 0)).collect(),
+    };
+
+    future::Either::A(
+        connection
+            .send(request)
+            .timeout(Duration::from_secs(300))
+            .flatten()
+            .and_then(move |blocks| {
+                for (b, (_, block_hash)) in blocks.iter().zip(batch) {
+                    if !filemap::verify_block(&b.bytes, block_hash) {
+                        return Err(crate::error::Error::InvalidBlockHash(block_hash));
+                    }
+                }
+                Ok(blocks)
+            })
+            .and_then(move |blocks| {
+                let size = blocks.iter().map(|b| b.bytes.len() as u64).sum();
+                ratelimit::throttle_download(size, bucket.as_deref()).map(move |()| blocks)
+            })
+            .map(move |mut blocks| {
+                // Replies come in request order, this file first.
+                let own = blocks.remove(0);
+                let mut small = small.lock().unwrap();
+                for b in blocks {
+                    small.fetched.insert(b.file_nr, b);
+                }
+                own
+            }),
+    )
+}
+
+/// Shares downloaded `files` as resource `hash` for `lifetime`.
+fn seed_files(
+    db: &Addr<DatabaseManager>,
+    hash: u128,
+    files: &[(FileMap, PathBuf)],
+    lifetime: Duration,
+    inline_threshold: u64,
+    reporter: user_report::UserReportHandle,
+) -> impl Future<Item = (), Error = crate::error::Error> {
+    let format = filemap::MapFormat::of(hash, files.iter().map(|(file_map, _)| file_map))
+        .ok_or(crate::error::Error::InvalidManifest(hash));
+    let register = format.and_then(|format| {
+        let mut files = files.to_vec();
+        inline_small_files(&mut files, inline_threshold)?;
+        Ok(RegisterHash {
+            files,
+            valid_to: Some(SystemTime::now() + lifetime),
+            reporter,
+            format,
+        })
+    });
+    let db = db.clone();
+
+    register
+        .into_future()
+        .and_then(move |register| db.send(register).flatten())
+        .map(move |seeded| log::info!("seeding {:032x}", seeded))
+}
+
+/// Moves a file already at `out_path` aside, to `.bak`. Returns where it went.
+fn backup_existing(out_path: &Path, reporter: &user_report::UserReportHandle) -> Option<PathBuf> {
+    if fs::symlink_metadata(out_path).is_ok() {
+        reporter.emit_warn(format!("path: {} already exists", out_path.display()));
+        log::warn!("path: {} already exists", out_path.display());
+        let backup = out_path.with_extension("bak");
+        if std::fs::rename(out_path, &backup).is_ok() {
+            return Some(backup);
+        }
+    }
+    None
+}
+
+/// File map of an uploaded entry. Directories and symlinks have no content.
+fn hash_entry(
+    format: filemap::MapFormat,
+    path: &Path,
+    file_name: String,
+    meta: Option<filemap::FileMeta>,
+) -> std::io::Result<FileMap> {
+    let is_link = meta.as_ref().is_some_and(|meta| meta.symlink.is_some());
+    let mut file_map = if file_name.ends_with('/') || is_link {
+        FileMap::empty(file_name)
+    } else {
+        filemap::hash_file_as(format, path, file_name)?
+    };
+    file_map.meta = meta;
+    Ok(file_map)
+}
+
+/// Creates symlinks and sets modes and modification times of downloaded `files`. Runs once all
+/// files are written, so none of them is written through a symlink of the resource.
+fn apply_meta(
+    files: &[(FileMap, PathBuf)],
+    reporter: &user_report::UserReportHandle,
+) -> Result<(), crate::error::Error> {
+    if let Some(link) = filemap::invalid_link(files.iter().map(|(file_map, _)| file_map)) {
+        return Err(crate::error::Error::InvalidFileName(link.file_name.clone()));
+    }
+    for (file_map, path) in files {
+        match (file_map.symlink(), &file_map.meta) {
+            (Some(target), _) => {
+                let target_path: PathBuf = target.split('/').collect();
+                if fs::read_link(path).ok() == Some(target_path) {
+                    continue;
+                }
+                backup_existing(path, reporter);
+                filemap::FileMeta::symlink(target, path)?;
+            }
+            (None, Some(meta)) => meta.apply(path)?,
+            (None, None) => (),
+        }
+    }
+    Ok(())
+}
+
+/// Leaves a hole of `len` zero bytes. Zeros are written if the file cannot seek, file systems
+/// without sparse files fill the hole with zeros themselves.
+fn skip_zeros(file: &mut fs::File, len: u64) -> std::io::Result<()> {
+    if file.seek(SeekFrom::Current(len as i64)).is_err() {
+        std::io::copy(&mut std::io::repeat(0).take(len), file)?;
+    }
+    Ok(())
+}
+
+fn file_info(file_map: FileMap) -> command::FileInfo {
+    command::FileInfo {
+        blocks: file_map.blocks.len(),
+        name: file_map.file_name,
+        size: file_map.file_size,
+    }
+}
+
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Where `file_map` goes under `dest`, with its parent directories created.
+fn out_path(dest: &Path, file_map: &FileMap) -> Result<PathBuf, crate::error::Error> {
+    let path = filemap::relative_path(&file_map.file_name)
+        .map(|path| dest.join(path))
+        .ok_or_else(|| crate::error::Error::InvalidFileName(file_map.file_name.clone()))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(path)
+}
+
+/// Gives `dest` its own copy of `files` downloaded to `src_dir`, hardlinked when possible.
+fn link_files(
+    src_dir: &Path,
+    files: Vec<(FileMap, PathBuf)>,
+    dest: &Path,
+    metadata: bool,
+    reporter: &user_report::UserReportHandle,
+) -> Result<Vec<(FileMap, PathBuf)>, crate::error::Error> {
+    let files = files
+        .into_iter()
+        .map(|(file_map, file)| {
+            let out_path = dest.join(file.strip_prefix(src_dir).unwrap_or(&file));
+            if file_map.symlink().is_some() {
+                return Ok((file_map, out_path));
+            }
+            if file_map.is_dir() {
+                fs::create_dir_all(&out_path)?;
+                return Ok((file_map, out_path));
+            }
+            if let Some(parent) = out_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            if is_same_file(&file, &out_path) {
+                return Ok((file_map, out_path));
+            }
+            backup_existing(&out_path, reporter);
+            if fs::hard_link(&file, &out_path).is_err() {
+                fs::copy(&file, &out_path)?;
+            }
+            Ok((file_map, out_path))
+        })
+        .collect::<Result<Vec<_>, crate::error::Error>>()?;
+    if metadata {
+        apply_meta(&files, reporter)?;
+    }
+    Ok(files)
 }
 
 fn resolve_host(src: &str) -> Result<IpAddr, <IpAddr as FromStr>::Err> {
@@ -92,9 +631,9 @@ fn resolve_host(src: &str) -> Result<IpAddr, <IpAddr as FromStr>::Err> {
 
 impl State {
     fn id(&self) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        database::id(&self.db)
-            .and_then(|id| {
-                let id = crate::codec::hash_to_hex(id);
+        database::identity(&self.db)
+            .and_then(|identity| {
+                let id = identity.public_key_hex();
                 let version = version::PACKAGE_VERSION.into();
                 Ok(HttpResponse::Ok().json(command::IdResult { id, version }))
             })
@@ -114,28 +653,43 @@ impl State {
         &self,
         files: impl IntoIterator<Item = (PathBuf, String)>,
         timeout: Option<f64>,
+        format: filemap::MapFormat,
+        filter: filemap::PathFilter,
+        metadata: bool,
         reporter: user_report::UserReportHandle,
     ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
-        let hashed: Result<Vec<(filemap::FileMap, PathBuf)>, _> = files
+        let hashed: Result<Vec<(filemap::FileMap, PathBuf)>, actix_web::error::Error> = files
             .into_iter()
-            .map(|(path, file_name)| Ok((filemap::hash_file(&path, file_name)?, path)))
-            .collect();
+            .map(|(path, file_name)| {
+                if !path.is_dir() {
+                    let meta = match metadata {
+                        true => Some(filemap::FileMeta::new(&fs::metadata(&path)?)),
+                        false => None,
+                    };
+                    return Ok(vec![(hash_entry(format, &path, file_name, meta)?, path)]);
+                }
+                filemap::dir_entries(&path, &file_name, &filter, metadata)?
+                    .into_iter()
+                    .map(|(path, file_name)| {
+                        let meta = match metadata {
+                            true => Some(filemap::FileMeta::read(&path)?),
+                            false => None,
+                        };
+                        Ok((hash_entry(format, &path, file_name, meta)?, path))
+                    })
+                    .collect()
+            })
+            .collect::<Result<Vec<Vec<_>>, std::io::Error>>()
+            .map(|entries| entries.into_iter().flatten().collect())
+            .map_err(Into::into);
 
         let db = self.db.clone();
+        let inline_threshold = self.opts.inline_threshold;
 
-        hashed.into_future().and_then(move |file_maps| {
-            let inline_data = if file_maps.len() == 1 {
-                if file_maps[0].0.file_size < 200 {
-                    match std::fs::read(&file_maps[0].1) {
-                        Ok(v) => v,
-                        Err(e) => return future::Either::B(future::err(e.into())),
-                    }
-                } else {
-                    Vec::new()
-                }
-            } else {
-                Vec::new()
-            };
+        hashed.into_future().and_then(move |mut file_maps| {
+            if let Err(e) = inline_small_files(&mut file_maps, inline_threshold) {
+                return future::Either::B(future::err(e.into()));
+            }
 
             // We do not trust timeout value for now.
             // Keeping file hash for 3 days should be good enough.
@@ -150,8 +704,8 @@ impl State {
                 db.send(RegisterHash {
                     files: file_maps,
                     valid_to,
-                    inline_data,
                     reporter,
+                    format,
                 })
                 .then(|r| match r {
                     Err(_e) => Err(actix_web::error::ErrorInternalServerError("database lost")),
@@ -193,7 +747,7 @@ impl State {
         hash: String,
         dest: PathBuf,
         peers: Vec<PeerInfo>,
-        _timeout: Option<f64>,
+        opts: DownloadOpts,
         reporter: user_report::UserReportHandle,
     ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
         let hash = match u128::from_str_radix(&hash, 16) {
@@ -201,101 +755,455 @@ impl State {
             Ok(hash) => hash,
         };
 
-        let peers: HashSet<_> = match peers
-            .into_iter()
-            .map(|peer_info| match peer_info {
-                PeerInfo::TCP(address, port) => Ok(SocketAddr::new(address.parse()?, port)),
-            })
-            .collect::<Result<_, std::net::AddrParseError>>()
-        {
+        let peers = match parse_peers(peers) {
             Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
             Ok(addrs) => addrs,
         };
+        let db = self.db.clone();
+        let transport = self.transport.clone();
+        let select = opts.select.clone();
+        // Only the whole resource can be shared under its hash.
+        let seed = opts.seed.filter(|_| select.is_all());
+        let seed_db = self.db.clone();
+        let seed_reporter = reporter.clone();
+        let inline_threshold = self.opts.inline_threshold;
+
+        // Other downloads of the resource may need files left out here.
+        let join = if select.is_all() {
+            Some(inflight::join(hash))
+        } else {
+            None
+        };
+        let files = match join {
+            None => future::Either::A(future::Either::B(Self::transfer(
+                hash, dest, peers, opts, db, transport, reporter,
+            ))),
+            Some(inflight::Join::Transfer(transfer)) => {
+                let out_dir = dest.clone();
+                future::Either::A(future::Either::A(
+                    Self::transfer(hash, dest, peers, opts, db, transport, reporter).map(
+                        move |(files, bytes_saved)| {
+                            transfer.finish(&out_dir, &files);
+                            (files, bytes_saved)
+                        },
+                    ),
+                ))
+            }
+            Some(inflight::Join::Wait(transfer)) => {
+                log::info!("download {:032x} joins transfer in progress", hash);
+                reporter.add_note(|| "joined transfer in progress".to_string());
+                future::Either::B(transfer.then(move |r| {
+                    match r {
+                        Ok((src_dir, files)) => future::Either::A(
+                            link_files(&src_dir, files, &dest, opts.metadata, &reporter)
+                                .map(|files| {
+                                    let size = files.iter().map(|(file_map, _)| file_map.file_size);
+                                    let bytes_saved = size.sum();
+                                    (files, bytes_saved)
+                                })
+                                .into_future(),
+                        ),
+                        // Transfer failed or was canceled, retry on our own.
+                        Err(_) => future::Either::B(Self::transfer(
+                            hash, dest, peers, opts, db, transport, reporter,
+                        )),
+                    }
+                }))
+            }
+        };
 
         future::Either::A(
-            find_peer(
-                hash,
-                self.db.clone(),
-                peers.into_iter().collect(),
-                reporter.clone(),
-            )
+            files
+                .and_then(move |(files, bytes_saved)| match seed {
+                    Some(lifetime) => future::Either::A(
+                        seed_files(
+                            &seed_db,
+                            hash,
+                            &files,
+                            lifetime,
+                            inline_threshold,
+                            seed_reporter,
+                        )
+                        .then(move |r| {
+                            if let Err(e) = r {
+                                log::warn!("failed to seed {:032x}: {}", hash, e);
+                            }
+                            Ok((files, bytes_saved))
+                        }),
+                    ),
+                    None => future::Either::B(future::ok((files, bytes_saved))),
+                })
+                .map(move |(files, bytes_saved)| {
+                    let matched = select.matched(files.iter().map(|(file_map, _)| file_map));
+                    let files = files.into_iter().map(|(_, path)| path).collect();
+                    HttpResponse::Ok().json(DownloadResult {
+                        files,
+                        bytes_saved,
+                        matched,
+                    })
+                })
+                .map_err(actix_web::error::ErrorInternalServerError),
+        )
+    }
+
+    /// Downloads all files of resource `hash` to `dest`. Returns them with the number of bytes
+    /// taken from local files.
+    fn transfer(
+        hash: u128,
+        dest: PathBuf,
+        peers: Vec<SocketAddr>,
+        opts: DownloadOpts,
+        db: Addr<DatabaseManager>,
+        transport: Arc<transport::TransportConfig>,
+        reporter: user_report::UserReportHandle,
+    ) -> impl Future<Item = (Vec<(FileMap, PathBuf)>, u64), Error = crate::error::Error> {
+        let bucket = opts
+            .limit
+            .map(|rate| Arc::new(Mutex::new(ratelimit::TokenBucket::new(rate))));
+        let bytes_saved = Arc::new(AtomicU64::new(0));
+        let total_saved = bytes_saved.clone();
+        let index = db.clone();
+        let metadata = opts.metadata;
+        let meta_reporter = reporter.clone();
+
+        find_peer(hash, db, peers, transport, reporter.clone())
             .and_then(move |(connection, file_map, peer): (_, Vec<FileMap>, _)| {
+                // Checked by `find_peer`, tells how local blocks are hashed.
+                let format = filemap::MapFormat::of(hash, &file_map).unwrap_or_default();
+                // Numbered as in the resource.
+                let file_map: Vec<(usize, FileMap)> = file_map
+                    .into_iter()
+                    .enumerate()
+                    .filter(|(_, file_map)| opts.select.includes(&file_map.file_name))
+                    .collect();
+                let block_hashes = file_map
+                    .iter()
+                    .flat_map(|(_, file_map)| file_map.blocks.iter().copied())
+                    .collect();
+                index
+                    .send(FindBlocks(block_hashes))
+                    .from_err()
+                    .join(basis_blocks(&index, opts.basis, format))
+                    .map(move |(mut local, basis)| {
+                        for (block_hash, location) in basis {
+                            local.entry(block_hash).or_insert(location);
+                        }
+                        let small = SmallBlocks::new(&file_map, &local);
+                        let local = Arc::new(Mutex::new(local));
+                        (
+                            connection,
+                            file_map,
+                            peer,
+                            local,
+                            Arc::new(Mutex::new(small)),
+                        )
+                    })
+            })
+            .and_then(move |(connection, file_map, peer, local, small)| {
                 use futures::prelude::*;
                 reporter.add_note(|| "got connection!".to_string());
                 reporter.annotate("peer", &peer);
+                let peer_id = connection.peer_id();
 
-                futures::stream::iter_ok(file_map.into_iter().enumerate())
+                futures::stream::iter_ok(file_map)
                     .and_then(move |(file_no, file_map)| {
                         let reporter = reporter.clone();
                         let hash = hash;
-                        let out_path = dest.join(&file_map.file_name);
+                        let out_path = match out_path(&dest, &file_map) {
+                            Ok(out_path) => out_path,
+                            Err(e) => return future::Either::B(future::err(e)),
+                        };
+                        if file_map.symlink().is_some() {
+                            // Created once all files are written.
+                            return future::Either::B(future::ok((file_map, out_path)));
+                        }
+                        if file_map.is_dir() {
+                            return future::Either::B(
+                                fs::create_dir_all(&out_path)
+                                    .map(|()| (file_map, out_path))
+                                    .map_err(Into::into)
+                                    .into_future(),
+                            );
+                        }
                         let connection = connection.clone();
+                        let bucket = bucket.clone();
+                        let local = local.clone();
+                        let small = small.clone();
+                        let bytes_saved = bytes_saved.clone();
+
+                        if let Some(backup) = backup_existing(&out_path, &reporter) {
+                            // Basis may be the old version of this very file.
+                            for location in local.lock().unwrap().values_mut() {
+                                if location.path == out_path {
+                                    location.path = backup.clone();
+                                }
+                            }
+                        }
 
-                        if out_path.exists() {
-                            reporter
-                                .emit_warn(format!("path: {} already exists", out_path.display()));
-                            log::warn!("path: {} already exists", out_path.display());
-                            let _ = std::fs::rename(&out_path, out_path.with_extension("bak"));
+                        // Came with the manifest, already checked against its block hash.
+                        if let Some(data) = &file_map.inline {
+                            return future::Either::B(
+                                fs::write(&out_path, data)
+                                    .map(|()| (file_map, out_path))
+                                    .map_err(Into::into)
+                                    .into_future(),
+                            );
                         }
 
-                        std::fs::OpenOptions::new()
-                            .write(true)
-                            .create_new(true)
-                            .open(&out_path)
-                            .into_future()
-                            .from_err()
-                            .and_then(move |mut out_file| {
-                                let block_reporter = reporter.clone();
-                                futures::stream::iter_ok(file_map.blocks.into_iter().enumerate())
-                                    .and_then(move |(block_no, block_hash_val)| {
+                        future::Either::A(
+                            std::fs::OpenOptions::new()
+                                .write(true)
+                                .create_new(true)
+                                .open(&out_path)
+                                .into_future()
+                                .from_err()
+                                .and_then(move |out_file| {
+                                    let block_reporter = reporter.clone();
+                                    let zero_sizes: Vec<_> = (0..file_map.blocks.len())
+                                        .map(|block_nr| {
+                                            file_map
+                                                .block_span(block_nr)
+                                                .filter(|_| file_map.is_zero_block(block_nr))
+                                                .map(|(_, size)| size)
+                                        })
+                                        .collect();
+                                    let file_size = file_map.file_size;
+                                    futures::stream::iter_ok(
+                                        file_map
+                                            .blocks
+                                            .clone()
+                                            .into_iter()
+                                            .zip(zero_sizes)
+                                            .enumerate(),
+                                    )
+                                    .and_then(move |(block_no, (block_hash_val, zero_size))| {
+                                        if let Some(size) = zero_size {
+                                            return future::Either::B(future::ok(Err(size)));
+                                        }
                                         reporter.add_note(|| {
                                             format!(
                                                 "start block block_no:{}, block_hash: {:032x}",
                                                 block_no, block_hash_val
                                             )
                                         });
-                                        connection
-                                            .send(GetBlock {
-                                                hash,
-                                                file_nr: file_no as u32,
-                                                block_nr: block_no as u32,
-                                            })
-                                            // min 110Kb/s
-                                            .timeout(Duration::from_secs(300))
-                                            .flatten()
-                                            .and_then(move |b| {
-                                                let block_hash_calc =
-                                                    hash_block(b.bytes.as_slice());
-                                                if block_hash_calc == block_hash_val {
-                                                    Ok(b)
-                                                } else {
-                                                    Err(crate::error::Error::InvalidBlockHash(
-                                                        block_hash_calc,
-                                                    ))
-                                                }
-                                            })
+                                        if small.lock().unwrap().contains(file_no as u32) {
+                                            return future::Either::A(future::Either::A(
+                                                get_small_block(
+                                                    connection.clone(),
+                                                    hash,
+                                                    file_no as u32,
+                                                    small.clone(),
+                                                    bucket.clone(),
+                                                )
+                                                .map(Ok),
+                                            ));
+                                        }
+                                        future::Either::A(future::Either::B(
+                                            get_block(
+                                                connection.clone(),
+                                                GetBlock {
+                                                    hash,
+                                                    file_nr: file_no as u32,
+                                                    block_nr: block_no as u32,
+                                                },
+                                                block_hash_val,
+                                                local.lock().unwrap().get(&block_hash_val).cloned(),
+                                                bucket.clone(),
+                                                bytes_saved.clone(),
+                                                reporter.clone(),
+                                            )
+                                            .map(Ok),
+                                        ))
                                     })
-                                    .for_each(move |b: Block| {
-                                        block_reporter.add_note(|| {
-                                            format!("writing block block_no:{}", b.block_nr)
-                                        });
-                                        out_file.write_all(b.bytes.as_slice())?;
-                                        Ok(())
+                                    .fold(out_file, move |mut out_file, block| {
+                                        match block {
+                                            Ok(b) => {
+                                                block_reporter.add_note(|| {
+                                                    format!("writing block block_no:{}", b.block_nr)
+                                                });
+                                                out_file.write_all(b.bytes.as_slice())?;
+                                            }
+                                            // Zero blocks are not fetched.
+                                            Err(size) => skip_zeros(&mut out_file, size as u64)?,
+                                        }
+                                        Ok::<_, crate::error::Error>(out_file)
                                     })
-                                    .and_then(|()| Ok(out_path))
-                            })
+                                    .and_then(
+                                        move |out_file| {
+                                            // Trailing hole.
+                                            out_file.set_len(file_size)?;
+                                            Ok((file_map, out_path))
+                                        },
+                                    )
+                                }),
+                        )
                     })
                     .collect()
-                    .and_then(|files| Ok(HttpResponse::Ok().json(DownloadResult { files: files })))
+                    .map_err(move |e| {
+                        reputation::report_error(Some(peer_id), peer.ip(), &e);
+                        e
+                    })
+            })
+            .and_then(move |files| {
+                if metadata {
+                    apply_meta(&files, &meta_reporter)?;
+                }
+                Ok((files, total_saved.load(Ordering::Relaxed)))
+            })
+    }
+
+    fn download_range(
+        &self,
+        hash: String,
+        range: command::FileRange,
+        dest: PathBuf,
+        peers: Vec<PeerInfo>,
+        reporter: user_report::UserReportHandle,
+    ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
+        let hash = match u128::from_str_radix(&hash, 16) {
+            Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
+            Ok(hash) => hash,
+        };
+        let peers = match parse_peers(peers) {
+            Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
+            Ok(addrs) => addrs,
+        };
+
+        let command::FileRange { file, offset, len } = range;
+        if len == 0 {
+            return future::Either::B(future::err(actix_web::error::ErrorBadRequest(
+                "empty range",
+            )));
+        }
+
+        future::Either::A(
+            find_peer(
+                hash,
+                self.db.clone(),
+                peers,
+                self.transport.clone(),
+                reporter.clone(),
+            )
+            .and_then(move |(connection, file_maps, peer): (_, Vec<FileMap>, _)| {
+                reporter.annotate("peer", &peer);
+                // Checked by `find_peer`, flat blocks can't be fetched in parts.
+                let format = filemap::MapFormat::of(hash, &file_maps).unwrap_or_default();
+                let (file_nr, file_map) = file_maps
+                    .into_iter()
+                    .enumerate()
+                    .find(|(_, file_map)| file_map.file_name == file)
+                    .ok_or(crate::error::Error::FileNotFound(file))?;
+                if offset >= file_map.file_size {
+                    return Err(crate::error::Error::InvalidRange(offset));
+                }
+                let end = std::cmp::min(offset.saturating_add(len), file_map.file_size);
+                Ok((connection, peer, file_nr, Arc::new(file_map), end, format))
             })
-            .map_err(actix_web::error::ErrorInternalServerError),
+            .and_then(move |(connection, peer, file_nr, file_map, end, format)| {
+                let peer_id = connection.peer_id();
+                let mut pieces = Vec::new();
+                let mut pos = offset;
+                while pos < end {
+                    let block_end = file_map
+                        .block_at(pos)
+                        .and_then(|block_nr| file_map.block_span(block_nr))
+                        .map_or(end, |(block_offset, size)| block_offset + size as u64);
+                    let piece_end = std::cmp::min(block_end, end);
+                    pieces.push((pos, (piece_end - pos) as u32));
+                    pos = piece_end;
+                }
+                let out_path = dest.clone();
+
+                futures::stream::iter_ok(pieces)
+                    .and_then(move |(offset, len)| {
+                        let piece = if format.has_chunk_roots() {
+                            future::Either::A(get_range(
+                                connection.clone(),
+                                hash,
+                                file_nr as u32,
+                                file_map.clone(),
+                                offset,
+                                len,
+                            ))
+                        } else {
+                            future::Either::B(get_block_range(
+                                connection.clone(),
+                                hash,
+                                file_nr as u32,
+                                file_map.clone(),
+                                offset,
+                                len,
+                            ))
+                        };
+                        piece.and_then(|bytes| {
+                            ratelimit::throttle_download(bytes.len() as u64, None)
+                                .map(move |()| bytes)
+                        })
+                    })
+                    .fold(None, move |out_file: Option<fs::File>, bytes| {
+                        // Created once the first piece checks out, so a failed request leaves
+                        // `dest` as it was.
+                        let mut out_file = match out_file {
+                            Some(out_file) => out_file,
+                            None => fs::OpenOptions::new()
+                                .write(true)
+                                .create(true)
+                                .truncate(true)
+                                .open(&out_path)?,
+                        };
+                        out_file.write_all(&bytes)?;
+                        Ok::<_, crate::error::Error>(Some(out_file))
+                    })
+                    .map_err(move |e| {
+                        reputation::report_error(Some(peer_id), peer.ip(), &e);
+                        e
+                    })
+                    .and_then(move |_| {
+                        Ok(HttpResponse::Ok().json(command::DownloadRangeResult {
+                            file: dest,
+                            offset,
+                            len: end - offset,
+                        }))
+                    })
+            })
+            .map_err(|e| match e {
+                crate::error::Error::InvalidRange(_) => actix_web::error::ErrorBadRequest(e),
+                e => actix_web::error::ErrorInternalServerError(e),
+            }),
         )
     }
 
+    /// Asks every peer for resource `hash` and describes it along with the peers that have it.
+    fn inspect(
+        &self,
+        hash: u128,
+        peers: Vec<SocketAddr>,
+        reporter: user_report::UserReportHandle,
+    ) -> impl Future<Item = command::InspectResult, Error = crate::error::Error> {
+        download::inspect(
+            hash,
+            self.db.clone(),
+            peers,
+            self.transport.clone(),
+            reporter,
+        )
+        .map(move |(file_maps, answered)| {
+            let total_size = file_maps.iter().map(|file_map| file_map.file_size).sum();
+            command::InspectResult {
+                hash: hash_to_hex(hash),
+                files: file_maps.into_iter().map(file_info).collect(),
+                total_size,
+                peers: answered.iter().map(ToString::to_string).collect(),
+            }
+        })
+    }
+
     fn mimic_download(
         &self,
         hash: String,
         dest: PathBuf,
+        metadata: bool,
+        select: filemap::FileSelection,
     ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
         let hash = match u128::from_str_radix(&hash, 16) {
             Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
@@ -307,28 +1215,52 @@ impl State {
             db.send(database::GetHash(hash))
                 .flatten()
                 .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-                .and_then(|o: Option<(Arc<database::FileDesc>, _)>| {
+                .and_then(move |o: Option<(Arc<database::FileDesc>, _)>| {
                     o.ok_or_else(|| actix_web::error::ErrorBadRequest("hash not found"))
                         .into_future()
                         .from_err()
-                        .and_then(|(desc, _)| {
-                            futures::stream::iter_ok(desc.files.to_vec().into_iter().enumerate())
-                                .and_then(move |(_, (file_map, path_buf))| {
-                                    let out_path = dest.join(&file_map.file_name);
-
-                                    if let Some(parent) = out_path.parent() {
-                                        // Copy fails either way if the parent path does not exist
-                                        let _ = fs::create_dir_all(parent);
-                                    }
-
-                                    fs::copy(path_buf, out_path.clone())
+                        .and_then(move |(desc, _)| {
+                            let files: Vec<_> = desc
+                                .files
+                                .iter()
+                                .filter(|(file_map, _)| select.includes(&file_map.file_name))
+                                .cloned()
+                                .collect();
+                            let bytes_saved: u64 =
+                                files.iter().map(|(file_map, _)| file_map.file_size).sum();
+                            let matched =
+                                select.matched(files.iter().map(|(file_map, _)| file_map));
+                            futures::stream::iter_ok(files)
+                                .and_then(move |(file_map, path_buf)| {
+                                    out_path(&dest, &file_map)
+                                        .and_then(|out_path| {
+                                            if file_map.is_dir() {
+                                                fs::create_dir_all(&out_path)?;
+                                            } else if file_map.symlink().is_none() {
+                                                fs::copy(path_buf, &out_path)?;
+                                            }
+                                            Ok((file_map, out_path))
+                                        })
                                         .into_future()
                                         .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-                                        .and_then(|_| Ok(out_path))
                                 })
                                 .collect()
+                                .and_then(move |files: Vec<(FileMap, PathBuf)>| {
+                                    if metadata {
+                                        apply_meta(&files, &user_report::UserReportHandle::empty())
+                                            .map_err(actix_web::error::ErrorInternalServerError)?;
+                                    }
+                                    Ok((files, bytes_saved, matched))
+                                })
+                        })
+                        .and_then(|(files, bytes_saved, matched)| {
+                            let files = files.into_iter().map(|(_, path)| path).collect();
+                            Ok(HttpResponse::Ok().json(DownloadResult {
+                                files,
+                                bytes_saved,
+                                matched,
+                            }))
                         })
-                        .and_then(|files| Ok(HttpResponse::Ok().json(DownloadResult { files })))
                 })
                 .map_err(actix_web::error::ErrorInternalServerError),
         )
@@ -344,15 +1276,43 @@ fn api(
     match body.0 {
         command::Command::Id => Box::new(state.id()),
         command::Command::Addresses => Box::new(state.addresses()),
+        command::Command::Limits { set } => {
+            if let Some(limits) = set {
+                ratelimit::set_limits(limits);
+            }
+            Box::new(future::ok(HttpResponse::Ok().json(limits_info())))
+        }
         command::Command::Upload {
             files: Some(files),
             timeout,
             hash: None,
+            format,
+            include,
+            exclude,
+            metadata,
             user,
         } => {
             let reporter = user_report::UserReportHandle::start(&user);
-            reporter.annotate("api", &("upload", &files, timeout));
-            Box::new(reporter.wrap_future("upload", state.upload(files, timeout, reporter.clone())))
+            reporter.annotate("api", &("upload", &files, timeout, format));
+            let filter = match filemap::PathFilter::new(&include, &exclude) {
+                Ok(filter) => filter,
+                Err(e) => {
+                    return Box::new(future::err(actix_web::error::ErrorBadRequest(
+                        e.to_string(),
+                    )))
+                }
+            };
+            Box::new(reporter.wrap_future(
+                "upload",
+                state.upload(
+                    files,
+                    timeout,
+                    format,
+                    filter,
+                    metadata.unwrap_or(state.opts.metadata),
+                    reporter.clone(),
+                ),
+            ))
         }
         command::Command::Upload {
             files: None,
@@ -370,21 +1330,100 @@ fn api(
             dest,
             peers,
             timeout,
+            limit,
+            seed,
+            seed_lifetime,
+            basis,
+            metadata,
+            files,
             user,
         } => {
             let reporter = user_report::UserReportHandle::start(&user);
-            reporter.annotate("api", &("download", &hash, &dest, &peers, timeout));
+            reporter.annotate("api", &("download", &hash, &dest, &peers, timeout, limit));
+            let select = match filemap::FileSelection::new(&files) {
+                Ok(select) => select,
+                Err(e) => {
+                    return Box::new(future::err(actix_web::error::ErrorBadRequest(
+                        e.to_string(),
+                    )))
+                }
+            };
+            let seed = if seed.unwrap_or(state.opts.seed) {
+                Some(Duration::from_secs(
+                    seed_lifetime.unwrap_or(state.opts.seed_lifetime),
+                ))
+            } else {
+                None
+            };
+            let metadata = metadata.unwrap_or(state.opts.metadata);
+            let opts = DownloadOpts {
+                limit,
+                seed,
+                basis,
+                metadata,
+                select: select.clone(),
+            };
             if peers.len() == 0 {
                 // Legacy HyperG behaviour:
                 // If no peers were provided, mimic the download process by copying locally stored files
-                Box::new(reporter.wrap_future("mimic_download", state.mimic_download(hash, dest)))
+                Box::new(reporter.wrap_future(
+                    "mimic_download",
+                    state.mimic_download(hash, dest, metadata, select),
+                ))
             } else {
                 Box::new(reporter.wrap_future(
                     "download",
-                    state.download(hash, dest, peers, timeout, reporter.clone()),
+                    state.download(hash, dest, peers, opts, reporter.clone()),
                 ))
             }
         }
+        command::Command::DownloadRange {
+            hash,
+            range,
+            dest,
+            peers,
+            timeout,
+            user,
+        } => {
+            let reporter = user_report::UserReportHandle::start(&user);
+            reporter.annotate(
+                "api",
+                &("downloadrange", &hash, &range, &dest, &peers, timeout),
+            );
+            Box::new(reporter.wrap_future(
+                "download_range",
+                state.download_range(hash, range, dest, peers, reporter.clone()),
+            ))
+        }
+        command::Command::Inspect {
+            hash,
+            peers,
+            timeout,
+            user,
+        } => {
+            let reporter = user_report::UserReportHandle::start(&user);
+            reporter.annotate("api", &("inspect", &hash, &peers, timeout));
+            let hash = match u128::from_str_radix(&hash, 16) {
+                Ok(hash) => hash,
+                Err(e) => return Box::new(future::err(actix_web::error::ErrorBadRequest(e))),
+            };
+            let peers = match parse_peers(peers) {
+                Ok(peers) if peers.is_empty() => {
+                    return Box::new(future::err(actix_web::error::ErrorBadRequest("no peers")))
+                }
+                Ok(peers) => peers,
+                Err(e) => return Box::new(future::err(actix_web::error::ErrorBadRequest(e))),
+            };
+            Box::new(
+                reporter.wrap_future(
+                    "inspect",
+                    state
+                        .inspect(hash, peers, reporter.clone())
+                        .map(|result| HttpResponse::Ok().json(result))
+                        .map_err(actix_web::error::ErrorInternalServerError),
+                ),
+            )
+        }
         other_command => {
             log::warn!("bad command: {:?}", other_command);
             Box::new(future::err(actix_web::error::ErrorBadRequest(format!(
@@ -431,15 +1470,33 @@ fn list_resources(
         })
 }
 
+#[derive(Deserialize)]
+struct ResourceQuery {
+    /// Comma separated `ip:port` of peers asked for resources not shared locally.
+    #[serde(default)]
+    peers: Option<String>,
+}
+
 #[get("/resources/{resourceId}")]
 fn get_resource_info(
     state: web::Data<State>,
     path: web::Path<(String,)>,
+    query: web::Query<ResourceQuery>,
 ) -> impl Future<Item = HttpResponse, Error = actix_web::error::Error> {
     let hash = match u128::from_str_radix(&path.0, 16) {
         Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
         Ok(hash) => hash,
     };
+    let peers = match query
+        .peers
+        .iter()
+        .flat_map(|peers| peers.split(','))
+        .map(SocketAddr::from_str)
+        .collect::<Result<Vec<_>, _>>()
+    {
+        Err(e) => return future::Either::B(future::err(actix_web::error::ErrorBadRequest(e))),
+        Ok(peers) => peers,
+    };
 
     future::Either::A(
         state
@@ -447,9 +1504,23 @@ fn get_resource_info(
             .send(database::GetHash(hash))
             .flatten()
             .map_err(|e| actix_web::error::ErrorInternalServerError(e))
-            .and_then(|r| match r {
-                None => Ok(HttpResponse::NotFound().body("resource not found")),
-                Some((file_desc, _)) => {
+            .and_then(move |r| match r {
+                None if peers.is_empty() => future::Either::A(future::ok(
+                    HttpResponse::NotFound().body("resource not found"),
+                )),
+                // Not shared here, described by peers instead.
+                None => future::Either::B(
+                    state
+                        .inspect(hash, peers, user_report::UserReportHandle::empty())
+                        .then(|r| match r {
+                            Ok(result) => Ok(HttpResponse::Ok().json(result)),
+                            Err(crate::error::Error::ResourceNotFound(_)) => {
+                                Ok(HttpResponse::NotFound().body("resource not found"))
+                            }
+                            Err(e) => Err(actix_web::error::ErrorInternalServerError(e)),
+                        }),
+                ),
+                Some((file_desc, _)) => future::Either::A({
                     let mut size: u64 = 0;
                     let files: Vec<(String, String)> = file_desc
                         .files
@@ -463,13 +1534,13 @@ fn get_resource_info(
                         .valid_to
                         .map(|ts| ts.duration_since(UNIX_EPOCH).unwrap().as_secs());
 
-                    Ok(HttpResponse::Ok().json(serde_json::json!({
+                    future::ok(HttpResponse::Ok().json(serde_json::json!({
                         "hash": hash_to_hex(file_desc.map_hash),
                         "files": files,
                         "totalSize": size,
                         "validTo": valid_to
                     })))
-                }
+                }),
             }),
     )
 }
@@ -496,6 +1567,56 @@ fn remove_resource(
     )
 }
 
+#[derive(Serialize)]
+struct LimitsInfo {
+    #[serde(flatten)]
+    limits: ratelimit::Limits,
+    active: ratelimit::Limits,
+}
+
+fn limits_info() -> LimitsInfo {
+    LimitsInfo {
+        limits: ratelimit::limits(),
+        active: ratelimit::active_limits(),
+    }
+}
+
+#[get("/limits")]
+fn get_limits() -> HttpResponse {
+    HttpResponse::Ok().json(limits_info())
+}
+
+#[put("/limits")]
+fn set_limits(body: web::Json<ratelimit::Limits>) -> HttpResponse {
+    ratelimit::set_limits(body.0);
+    HttpResponse::Ok().json(limits_info())
+}
+
+#[get("/connections")]
+fn get_connections() -> HttpResponse {
+    HttpResponse::Ok().json(admission::stats())
+}
+
+#[get("/bans")]
+fn list_bans() -> HttpResponse {
+    HttpResponse::Ok().json(reputation::bans())
+}
+
+#[delete("/bans")]
+fn clear_bans() -> HttpResponse {
+    reputation::clear(None);
+    HttpResponse::NoContent().finish()
+}
+
+#[delete("/bans/{peer}")]
+fn clear_ban(path: web::Path<(String,)>) -> HttpResponse {
+    match reputation::PeerKey::try_from(path.0.clone()) {
+        Err(e) => HttpResponse::BadRequest().body(e),
+        Ok(peer) if reputation::clear(Some(peer)) => HttpResponse::NoContent().finish(),
+        Ok(_) => HttpResponse::NotFound().body("peer not found"),
+    }
+}
+
 fn main() -> std::io::Result<()> {
     user_report::init();
     let args = ServerOpts::from_args();
@@ -510,12 +1631,39 @@ fn main() -> std::io::Result<()> {
 
     let sys = actix::System::new("hyperg");
 
+    ratelimit::set_limits(ratelimit::Limits {
+        upload: args.upload_limit,
+        peer_upload: args.peer_upload_limit,
+        resource_upload: args.resource_upload_limit,
+        download: args.download_limit,
+    });
+    admission::set_limits(admission::ConnectionLimits {
+        max_connections: args.max_connections,
+        max_per_ip: args.max_connections_per_ip,
+        max_pending: args.max_pending_connections,
+    });
+    if let Some(path) = &args.schedule {
+        let schedule = ratelimit::load_schedule(path)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e.to_string()))?;
+        ratelimit::set_schedule(schedule);
+    }
+
+    keepalive::set_config(keepalive::Config {
+        interval: Some(Duration::from_secs(args.keepalive_interval)).filter(|d| d.as_secs() > 0),
+        peer_timeout: Duration::from_secs(args.peer_timeout),
+        idle_timeout: Some(Duration::from_secs(args.idle_timeout)).filter(|d| d.as_secs() > 0),
+        pool_idle: Duration::from_secs(args.pool_idle),
+    });
+    pool::start_sweeper();
+    reputation::init(&database::db_dir(&args.db));
     let db = database::database_manager(&args.db);
+    let transport =
+        transport::TransportConfig::new(args.encryption).expect("failed to generate transport key");
     let opts = Arc::new(args);
 
     let server_opts = opts.clone();
 
-    let _transfer_server = server::new(db.clone(), (opts.host, opts.port))?;
+    let _transfer_server = server::new(db.clone(), (opts.host, opts.port), transport.clone())?;
 
     let _rpc_server = HttpServer::new(move || {
         App::new()
@@ -523,10 +1671,17 @@ fn main() -> std::io::Result<()> {
             .data(State {
                 db: db.clone(),
                 opts: opts.clone(),
+                transport: transport.clone(),
             })
             .service(list_resources)
             .service(get_resource_info)
             .service(remove_resource)
+            .service(get_limits)
+            .service(set_limits)
+            .service(get_connections)
+            .service(list_bans)
+            .service(clear_bans)
+            .service(clear_ban)
             .service(api)
     })
     .bind((server_opts.rpc_host, server_opts.rpc_port))?
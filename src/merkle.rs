@@ -0,0 +1,149 @@
+//! Binary hash tree with u128 nodes. Leaf and node hashes are domain separated, odd nodes
+//! are carried up to the next level unchanged.
+
+use sha2::digest::Digest;
+use std::convert::TryInto;
+
+const LEAF_PREFIX: u8 = 0;
+
+const NODE_PREFIX: u8 = 1;
+
+#[inline]
+fn extract_results<D: Digest>(digest: D) -> u128 {
+    u128::from_le_bytes(digest.result()[0..16].try_into().unwrap())
+}
+
+pub fn leaf_hash(data: &[u8]) -> u128 {
+    let mut digest = sha2::Sha224::new();
+    digest.input([LEAF_PREFIX]);
+    digest.input(data);
+    extract_results(digest)
+}
+
+pub fn node_hash(left: u128, right: u128) -> u128 {
+    let mut digest = sha2::Sha224::new();
+    digest.input([NODE_PREFIX]);
+    digest.input(left.to_le_bytes());
+    digest.input(right.to_le_bytes());
+    extract_results(digest)
+}
+
+fn next_level(level: &[u128]) -> Vec<u128> {
+    level
+        .chunks(2)
+        .map(|pair| match pair {
+            [left, right] => node_hash(*left, *right),
+            [single] => *single,
+            _ => unreachable!(),
+        })
+        .collect()
+}
+
+pub fn root(leaves: &[u128]) -> u128 {
+    assert!(!leaves.is_empty(), "empty merkle tree");
+    let mut level = leaves.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level[0]
+}
+
+/// Hashes needed to compute the root from leaves `start..start + count`.
+pub fn range_proof(leaves: &[u128], start: usize, count: usize) -> Vec<u128> {
+    assert!(count > 0 && start + count <= leaves.len());
+    let mut proof = Vec::new();
+    let mut level = leaves.to_vec();
+    let (mut lo, mut hi) = (start, start + count - 1);
+
+    while level.len() > 1 {
+        if lo & 1 == 1 {
+            proof.push(level[lo - 1]);
+        }
+        if hi & 1 == 0 && hi + 1 < level.len() {
+            proof.push(level[hi + 1]);
+        }
+        level = next_level(&level);
+        lo /= 2;
+        hi /= 2;
+    }
+    proof
+}
+
+/// Checks `range` of leaves starting at `start` against `root` of a tree with `leaf_count`
+/// leaves.
+pub fn verify_range(
+    root: u128,
+    leaf_count: usize,
+    start: usize,
+    range: &[u128],
+    proof: &[u128],
+) -> bool {
+    if range.is_empty() || start + range.len() > leaf_count {
+        return false;
+    }
+    let mut proof = proof.iter();
+    let mut nodes = range.to_vec();
+    let mut lo = start;
+    let mut level_len = leaf_count;
+
+    while level_len > 1 {
+        let hi = lo + nodes.len() - 1;
+        if lo & 1 == 1 {
+            match proof.next() {
+                Some(&left) => nodes.insert(0, left),
+                None => return false,
+            }
+        }
+        if hi & 1 == 0 && hi + 1 < level_len {
+            match proof.next() {
+                Some(&right) => nodes.push(right),
+                None => return false,
+            }
+        }
+        nodes = next_level(&nodes);
+        lo /= 2;
+        level_len = level_len.div_ceil(2);
+    }
+
+    proof.next().is_none() && nodes.len() == 1 && nodes[0] == root
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_single_leaf() {
+        let leaves = [leaf_hash(b"data")];
+        assert_eq!(root(&leaves), leaves[0]);
+        assert!(range_proof(&leaves, 0, 1).is_empty());
+        assert!(verify_range(leaves[0], 1, 0, &leaves, &[]));
+    }
+
+    #[test]
+    fn test_all_ranges() {
+        for n in 1..12usize {
+            let leaves: Vec<u128> = (0..n).map(|i| leaf_hash(&i.to_le_bytes())).collect();
+            let r = root(&leaves);
+            for start in 0..n {
+                for count in 1..=n - start {
+                    let proof = range_proof(&leaves, start, count);
+                    let range = &leaves[start..start + count];
+                    assert!(verify_range(r, n, start, range, &proof));
+                    assert!(!verify_range(r ^ 1, n, start, range, &proof));
+                    if start > 0 {
+                        assert!(!verify_range(r, n, start - 1, range, &proof));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_tampered_leaf() {
+        let leaves: Vec<u128> = (0..5u32).map(|i| leaf_hash(&i.to_le_bytes())).collect();
+        let proof = range_proof(&leaves, 1, 2);
+        let tampered = [leaves[1], leaf_hash(b"other")];
+        assert!(!verify_range(root(&leaves), 5, 1, &tampered, &proof));
+    }
+}
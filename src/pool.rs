@@ -0,0 +1,275 @@
+//! Established peer connections reused across downloads. A peer serves one resource per
+//! connection at a time, so a connection is leased for a resource: downloads of the same resource
+//! share it, others get it once it is free. Free connections expire after the keepalive `pool_idle`,
+//! checked by a sweeper even while no download uses the pool.
+
+use crate::connection::{Connection, ConnectionRef};
+use crate::keepalive;
+use actix::prelude::*;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Shortest time between two sweeps of the global pool.
+const MIN_SWEEP: Duration = Duration::from_secs(1);
+
+pub trait Pooled {
+    fn is_connected(&self) -> bool;
+
+    fn peer_id(&self) -> u128;
+}
+
+impl Pooled for ConnectionRef {
+    fn is_connected(&self) -> bool {
+        self.connected()
+    }
+
+    fn peer_id(&self) -> u128 {
+        ConnectionRef::peer_id(self)
+    }
+}
+
+struct Entry<C> {
+    connection: C,
+    peer: SocketAddr,
+    resource: u128,
+    leases: usize,
+    idle_since: Instant,
+}
+
+impl<C: Pooled> Entry<C> {
+    fn can_serve(&self, resource: u128) -> bool {
+        self.connection.is_connected() && (self.leases == 0 || self.resource == resource)
+    }
+}
+
+struct Pool<C> {
+    next_id: usize,
+    entries: HashMap<usize, Entry<C>>,
+    /// Free connections are closed after this long.
+    idle: Duration,
+}
+
+impl<C> Pool<C> {
+    fn new(idle: Duration) -> Self {
+        Pool {
+            next_id: 0,
+            entries: HashMap::new(),
+            idle,
+        }
+    }
+}
+
+impl<C: Pooled> Pool<C> {
+    /// Drops disconnected connections and the ones free for too long.
+    fn expire(&mut self, now: Instant) {
+        let idle = self.idle;
+        self.entries.retain(|_, entry| {
+            entry.connection.is_connected()
+                && (entry.leases > 0 || now.saturating_duration_since(entry.idle_since) < idle)
+        });
+    }
+
+    fn lease(&mut self, id: usize, resource: u128) -> usize {
+        let entry = self.entries.get_mut(&id).unwrap();
+        entry.resource = resource;
+        entry.leases += 1;
+        id
+    }
+
+    fn acquire(&mut self, peer: SocketAddr, resource: u128, now: Instant) -> Option<usize> {
+        self.expire(now);
+        let id = self
+            .entries
+            .iter()
+            .find(|(_, entry)| entry.peer == peer && entry.can_serve(resource))
+            .map(|(id, _)| *id)?;
+        Some(self.lease(id, resource))
+    }
+
+    /// Adds a new connection, unless one to the same node can be used instead.
+    fn insert(&mut self, connection: C, peer: SocketAddr, resource: u128, now: Instant) -> usize {
+        self.expire(now);
+        let peer_id = connection.peer_id();
+        let existing = self
+            .entries
+            .iter()
+            .find(|(_, entry)| entry.connection.peer_id() == peer_id && entry.can_serve(resource))
+            .map(|(id, _)| *id);
+        if let Some(id) = existing {
+            return self.lease(id, resource);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.entries.insert(
+            id,
+            Entry {
+                connection,
+                peer,
+                resource,
+                leases: 0,
+                idle_since: now,
+            },
+        );
+        self.lease(id, resource)
+    }
+
+    fn release(&mut self, id: usize, now: Instant) {
+        if let Some(entry) = self.entries.get_mut(&id) {
+            entry.leases -= 1;
+            if entry.leases == 0 {
+                entry.idle_since = now;
+            }
+        }
+    }
+
+    fn get(&self, id: usize) -> &C {
+        &self.entries[&id].connection
+    }
+}
+
+fn pool() -> &'static Mutex<Pool<ConnectionRef>> {
+    static POOL: OnceLock<Mutex<Pool<ConnectionRef>>> = OnceLock::new();
+    POOL.get_or_init(|| Mutex::new(Pool::new(keepalive::config().pool_idle)))
+}
+
+/// Expires free connections of the global pool between downloads.
+struct Sweeper;
+
+impl Actor for Sweeper {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let every = (keepalive::config().pool_idle / 2).max(MIN_SWEEP);
+        let _ = ctx.run_interval(every, |_, _| {
+            pool().lock().unwrap().expire(Instant::now());
+        });
+    }
+}
+
+/// Starts expiring idle connections on a timer, needs a running system.
+pub fn start_sweeper() {
+    let _ = Sweeper.start();
+}
+
+/// Connection leased for one resource, returned to the pool on drop.
+pub struct Lease {
+    id: usize,
+    addr: Addr<Connection>,
+    peer_id: u128,
+}
+
+impl Lease {
+    fn new(pool: &Pool<ConnectionRef>, id: usize) -> Self {
+        let connection = pool.get(id);
+        Lease {
+            id,
+            addr: (**connection).clone(),
+            peer_id: connection.peer_id(),
+        }
+    }
+
+    /// Node id proven by the peer.
+    pub fn peer_id(&self) -> u128 {
+        self.peer_id
+    }
+}
+
+impl Deref for Lease {
+    type Target = Addr<Connection>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.addr
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        pool().lock().unwrap().release(self.id, Instant::now())
+    }
+}
+
+/// Free connection to `peer`, or one already used for `resource`.
+pub fn acquire(peer: SocketAddr, resource: u128) -> Option<Lease> {
+    let mut pool = pool().lock().unwrap();
+    let id = pool.acquire(peer, resource, Instant::now())?;
+    Some(Lease::new(&pool, id))
+}
+
+/// Pools a newly established connection.
+pub fn insert(connection: ConnectionRef, peer: SocketAddr, resource: u128) -> Lease {
+    let mut pool = pool().lock().unwrap();
+    let id = pool.insert(connection, peer, resource, Instant::now());
+    Lease::new(&pool, id)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    struct Fake {
+        peer_id: u128,
+        connected: Rc<Cell<bool>>,
+    }
+
+    impl Pooled for Fake {
+        fn is_connected(&self) -> bool {
+            self.connected.get()
+        }
+
+        fn peer_id(&self) -> u128 {
+            self.peer_id
+        }
+    }
+
+    fn fake(peer_id: u128) -> (Fake, Rc<Cell<bool>>) {
+        let connected = Rc::new(Cell::new(true));
+        (
+            Fake {
+                peer_id,
+                connected: connected.clone(),
+            },
+            connected,
+        )
+    }
+
+    #[test]
+    fn test_pool() {
+        let now = Instant::now();
+        let peer: SocketAddr = "10.0.0.1:3282".parse().unwrap();
+        let other: SocketAddr = "10.0.0.2:3282".parse().unwrap();
+        let mut pool = Pool::new(Duration::from_secs(60));
+        assert_eq!(pool.acquire(peer, 1, now), None);
+
+        let (a, _) = fake(7);
+        let id = pool.insert(a, peer, 1, now);
+        // Same resource shares the connection, another one has to wait.
+        assert_eq!(pool.acquire(peer, 1, now), Some(id));
+        assert_eq!(pool.acquire(peer, 2, now), None);
+        pool.release(id, now);
+        pool.release(id, now);
+        assert_eq!(pool.acquire(peer, 2, now), Some(id));
+        assert_eq!(pool.acquire(other, 2, now), None);
+
+        // Node reached at another address reuses its connection.
+        let (b, _) = fake(7);
+        assert_eq!(pool.insert(b, other, 2, now), id);
+        assert_eq!(pool.entries.len(), 1);
+        pool.release(id, now);
+        pool.release(id, now);
+
+        assert_eq!(pool.acquire(peer, 3, now + pool.idle), None);
+        assert!(pool.entries.is_empty());
+
+        let (c, c_connected) = fake(8);
+        let id = pool.insert(c, peer, 1, now);
+        pool.release(id, now);
+        c_connected.set(false);
+        assert_eq!(pool.acquire(peer, 1, now), None);
+        assert!(pool.entries.is_empty());
+    }
+}
@@ -0,0 +1,390 @@
+//! Token buckets limiting block traffic. Uploads are limited globally, per peer address and per
+//! resource, downloads globally. Limits may change during the day according to a schedule.
+
+use crate::error::Error;
+use chrono::NaiveTime;
+use futures::{future, Future};
+use serde::{Deserialize, Serialize};
+use std::cmp::max;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+/// Buckets unused for this long are dropped.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the schedule is checked for a new window.
+const SCHEDULE_CHECK: Duration = Duration::from_secs(30);
+
+/// Rates in bytes per second, `None` for no limit.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Limits {
+    pub upload: Option<u64>,
+    pub peer_upload: Option<u64>,
+    pub resource_upload: Option<u64>,
+    pub download: Option<u64>,
+}
+
+/// Limits applied between `from` and `to` local time. Windows ending before they start wrap
+/// around midnight.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Window {
+    #[serde(with = "hhmm")]
+    pub from: NaiveTime,
+    #[serde(with = "hhmm")]
+    pub to: NaiveTime,
+    #[serde(flatten)]
+    pub limits: Limits,
+}
+
+impl Window {
+    fn contains(&self, time: NaiveTime) -> bool {
+        if self.from <= self.to {
+            self.from <= time && time < self.to
+        } else {
+            self.from <= time || time < self.to
+        }
+    }
+}
+
+mod hhmm {
+    use chrono::NaiveTime;
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%H:%M";
+
+    pub fn serialize<S: Serializer>(time: &NaiveTime, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&time.format(FORMAT).to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<NaiveTime, D::Error> {
+        let s = String::deserialize(d)?;
+        NaiveTime::parse_from_str(&s, FORMAT).map_err(de::Error::custom)
+    }
+}
+
+/// Reads schedule, a JSON list of windows.
+pub fn load_schedule(path: &Path) -> Result<Vec<Window>, Error> {
+    let file = std::fs::File::open(path)?;
+    Ok(serde_json::from_reader(std::io::BufReader::new(file))?)
+}
+
+/// Limits of the first window containing `time`, `base` if there is none.
+fn limits_at(base: Limits, schedule: &[Window], time: NaiveTime) -> Limits {
+    schedule
+        .iter()
+        .find(|window| window.contains(time))
+        .map_or(base, |window| window.limits)
+}
+
+pub struct TokenBucket {
+    rate: u64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// Bucket holds at most one second worth of tokens.
+    pub fn new(rate: u64) -> Self {
+        Self::new_at(rate, Instant::now())
+    }
+
+    fn new_at(rate: u64, now: Instant) -> Self {
+        TokenBucket {
+            rate,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    /// Takes `bytes` and returns how long the caller has to wait until they are covered.
+    pub fn reserve(&mut self, bytes: u64) -> Duration {
+        self.reserve_at(bytes, Instant::now())
+    }
+
+    fn reserve_at(&mut self, bytes: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+        self.last = now;
+        self.tokens -= bytes as f64;
+        if self.tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate as f64)
+        }
+    }
+}
+
+#[derive(Default)]
+struct Limiter {
+    base: Limits,
+    schedule: Vec<Window>,
+    active: Limits,
+    checked: Option<Instant>,
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+    peers: HashMap<IpAddr, TokenBucket>,
+    resources: HashMap<u128, TokenBucket>,
+}
+
+fn reserve_in<K: std::hash::Hash + Eq>(
+    buckets: &mut HashMap<K, TokenBucket>,
+    key: K,
+    rate: u64,
+    bytes: u64,
+    now: Instant,
+) -> Duration {
+    buckets.retain(|_, bucket| now.saturating_duration_since(bucket.last) < IDLE_TIMEOUT);
+    buckets
+        .entry(key)
+        .or_insert_with(|| TokenBucket::new_at(rate, now))
+        .reserve_at(bytes, now)
+}
+
+impl Limiter {
+    /// Switches to `limits`, buckets start full.
+    fn activate(&mut self, limits: Limits, now: Instant) {
+        log::info!("active limits: {:?}", limits);
+        self.active = limits;
+        self.upload = limits.upload.map(|rate| TokenBucket::new_at(rate, now));
+        self.download = limits.download.map(|rate| TokenBucket::new_at(rate, now));
+        self.peers.clear();
+        self.resources.clear();
+    }
+
+    /// Follows the schedule, local time is looked up at most every `SCHEDULE_CHECK`.
+    fn refresh(&mut self, now: Instant, time: impl FnOnce() -> NaiveTime) {
+        if self.schedule.is_empty() {
+            return;
+        }
+        if let Some(checked) = self.checked {
+            if now.saturating_duration_since(checked) < SCHEDULE_CHECK {
+                return;
+            }
+        }
+        self.checked = Some(now);
+        let limits = limits_at(self.base, &self.schedule, time());
+        if limits != self.active {
+            self.activate(limits, now);
+        }
+    }
+
+    fn set_limits(&mut self, base: Limits, now: Instant) {
+        self.base = base;
+        self.checked = None;
+        self.activate(base, now);
+    }
+
+    fn reserve_upload(
+        &mut self,
+        peer: IpAddr,
+        resource: u128,
+        bytes: u64,
+        now: Instant,
+    ) -> Duration {
+        let mut delay = Duration::from_secs(0);
+        if let Some(bucket) = &mut self.upload {
+            delay = max(delay, bucket.reserve_at(bytes, now));
+        }
+        if let Some(rate) = self.active.peer_upload {
+            delay = max(delay, reserve_in(&mut self.peers, peer, rate, bytes, now));
+        }
+        if let Some(rate) = self.active.resource_upload {
+            delay = max(
+                delay,
+                reserve_in(&mut self.resources, resource, rate, bytes, now),
+            );
+        }
+        delay
+    }
+
+    fn reserve_download(&mut self, bytes: u64, now: Instant) -> Duration {
+        match &mut self.download {
+            Some(bucket) => bucket.reserve_at(bytes, now),
+            None => Duration::from_secs(0),
+        }
+    }
+}
+
+fn limiter() -> &'static Mutex<Limiter> {
+    static LIMITER: OnceLock<Mutex<Limiter>> = OnceLock::new();
+    LIMITER.get_or_init(Default::default)
+}
+
+fn local_time() -> NaiveTime {
+    chrono::Local::now().time()
+}
+
+/// Limits used outside of scheduled windows.
+pub fn limits() -> Limits {
+    limiter().lock().unwrap().base
+}
+
+/// Limits in effect right now.
+pub fn active_limits() -> Limits {
+    let mut limiter = limiter().lock().unwrap();
+    limiter.refresh(Instant::now(), local_time);
+    limiter.active
+}
+
+/// Replaces limits used outside of scheduled windows.
+pub fn set_limits(limits: Limits) {
+    log::info!("limits: {:?}", limits);
+    limiter().lock().unwrap().set_limits(limits, Instant::now())
+}
+
+pub fn set_schedule(schedule: Vec<Window>) {
+    let mut limiter = limiter().lock().unwrap();
+    limiter.schedule = schedule;
+    limiter.checked = None;
+    limiter.refresh(Instant::now(), local_time);
+}
+
+/// Accounts `bytes` sent to `peer` from `resource`, returns delay before sending them.
+pub fn reserve_upload(peer: IpAddr, resource: u128, bytes: u64) -> Duration {
+    let now = Instant::now();
+    let mut limiter = limiter().lock().unwrap();
+    limiter.refresh(now, local_time);
+    limiter.reserve_upload(peer, resource, bytes, now)
+}
+
+/// Accounts `bytes` received, returns delay before reading more.
+pub fn reserve_download(bytes: u64) -> Duration {
+    let now = Instant::now();
+    let mut limiter = limiter().lock().unwrap();
+    limiter.refresh(now, local_time);
+    limiter.reserve_download(bytes, now)
+}
+
+/// Waits until `bytes` received fit the global download limit and `bucket` of a single download.
+pub fn throttle_download(
+    bytes: u64,
+    bucket: Option<&Mutex<TokenBucket>>,
+) -> impl Future<Item = (), Error = Error> {
+    let mut delay = reserve_download(bytes);
+    if let Some(bucket) = bucket {
+        delay = max(delay, bucket.lock().unwrap().reserve(bytes));
+    }
+    if delay == Duration::from_secs(0) {
+        return future::Either::A(future::ok(()));
+    }
+    future::Either::B(
+        tokio_timer::Delay::new(Instant::now() + delay)
+            .map_err(|_| Error::ServiceFail("download timer")),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hm(h: u32, m: u32) -> NaiveTime {
+        NaiveTime::from_hms(h, m, 0)
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new_at(1000, now);
+        assert_eq!(bucket.reserve_at(1000, now), Duration::from_secs(0));
+        assert_eq!(bucket.reserve_at(500, now), Duration::from_millis(500));
+        // Debt is paid off before new tokens are available.
+        let later = now + Duration::from_secs(1);
+        assert_eq!(bucket.reserve_at(500, later), Duration::from_secs(0));
+        // Idle time does not accumulate more than one second of tokens.
+        let much_later = later + Duration::from_secs(10);
+        assert_eq!(bucket.reserve_at(2000, much_later), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_limiter() {
+        let now = Instant::now();
+        let peer_a: IpAddr = "10.0.0.1".parse().unwrap();
+        let peer_b: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut limiter = Limiter::default();
+        assert_eq!(
+            limiter.reserve_upload(peer_a, 1, 1 << 30, now),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            limiter.reserve_download(1 << 30, now),
+            Duration::from_secs(0)
+        );
+
+        limiter.set_limits(
+            Limits {
+                upload: Some(2000),
+                peer_upload: Some(1000),
+                download: Some(1000),
+                ..Limits::default()
+            },
+            now,
+        );
+        assert_eq!(
+            limiter.reserve_upload(peer_a, 1, 2000, now),
+            Duration::from_secs(1)
+        );
+        // Peer b has a full bucket, but global one is empty.
+        assert_eq!(
+            limiter.reserve_upload(peer_b, 2, 1000, now),
+            Duration::from_millis(500)
+        );
+        // Downloads have their own bucket.
+        assert_eq!(limiter.reserve_download(2000, now), Duration::from_secs(1));
+
+        limiter.set_limits(
+            Limits {
+                resource_upload: Some(1000),
+                ..Limits::default()
+            },
+            now,
+        );
+        assert_eq!(
+            limiter.reserve_upload(peer_a, 1, 1000, now),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            limiter.reserve_upload(peer_b, 1, 1000, now),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            limiter.reserve_upload(peer_b, 2, 1000, now),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn test_schedule() {
+        let schedule: Vec<Window> = serde_json::from_str(
+            r#"[
+                {"from": "09:00", "to": "17:00", "download": 1000, "upload": 500},
+                {"from": "22:00", "to": "06:00"}
+            ]"#,
+        )
+        .unwrap();
+        let base = Limits {
+            download: Some(5000),
+            ..Limits::default()
+        };
+        assert_eq!(limits_at(base, &schedule, hm(8, 59)), base);
+        assert_eq!(limits_at(base, &schedule, hm(9, 0)).upload, Some(500));
+        assert_eq!(limits_at(base, &schedule, hm(17, 0)), base);
+        // Night window wraps midnight and lifts all limits.
+        assert_eq!(limits_at(base, &schedule, hm(23, 30)), Limits::default());
+        assert_eq!(limits_at(base, &schedule, hm(5, 59)), Limits::default());
+
+        let now = Instant::now();
+        let mut limiter = Limiter::default();
+        limiter.set_limits(base, now);
+        limiter.schedule = schedule;
+        limiter.refresh(now, || hm(10, 0));
+        assert_eq!(limiter.active.download, Some(1000));
+        // Time is not looked up again before `SCHEDULE_CHECK` passes.
+        limiter.refresh(now + Duration::from_secs(1), || unreachable!());
+        limiter.refresh(now + SCHEDULE_CHECK, || hm(18, 0));
+        assert_eq!(limiter.active, base);
+    }
+}
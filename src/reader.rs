@@ -0,0 +1,130 @@
+//! Disk reads for served blocks. They run on the blocking thread pool, so a slow disk does not
+//! stall connection actors. Open files are cached, serving a block costs one positional read.
+
+use crate::error::Error;
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+const MAX_OPEN_FILES: usize = 256;
+
+#[derive(Default)]
+struct FileCache {
+    files: HashMap<PathBuf, Arc<File>>,
+    order: VecDeque<PathBuf>,
+}
+
+impl FileCache {
+    fn open(&mut self, path: &Path) -> io::Result<Arc<File>> {
+        if let Some(file) = self.files.get(path) {
+            return Ok(file.clone());
+        }
+        let file = Arc::new(OpenOptions::new().read(true).open(path)?);
+        if self.order.len() >= MAX_OPEN_FILES {
+            if let Some(oldest) = self.order.pop_front() {
+                self.files.remove(&oldest);
+            }
+        }
+        self.order.push_back(path.to_owned());
+        self.files.insert(path.to_owned(), file.clone());
+        Ok(file)
+    }
+
+    fn close(&mut self, path: &Path) {
+        if self.files.remove(path).is_some() {
+            self.order.retain(|p| p != path);
+        }
+    }
+}
+
+fn cache() -> &'static Mutex<FileCache> {
+    static CACHE: OnceLock<Mutex<FileCache>> = OnceLock::new();
+    CACHE.get_or_init(Default::default)
+}
+
+/// Runs `f` on the blocking thread pool.
+pub fn run<F, T>(f: F) -> impl Future<Item = T, Error = Error>
+where
+    F: FnOnce() -> Result<T, io::Error> + Send + 'static,
+    T: Send + 'static,
+{
+    actix_threadpool::run(f).map_err(|e| match e {
+        actix_threadpool::BlockingError::Error(e) => e.into(),
+        actix_threadpool::BlockingError::Canceled => Error::ServiceFail("blocking pool"),
+    })
+}
+
+/// Fills `buf` from `path` at `offset` using a cached file handle.
+pub fn read_exact_at(path: &Path, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    let file = cache().lock().unwrap().open(path)?;
+    let result = read_at(&file, offset, buf);
+    if result.is_err() {
+        // File may have been replaced, reopen on next read.
+        close(path);
+    }
+    result
+}
+
+/// Drops cached handle of `path`, called when a file is no longer shared.
+pub fn close(path: &Path) {
+    cache().lock().unwrap().close(path)
+}
+
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_at(file: &File, mut offset: u64, mut buf: &mut [u8]) -> io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "unexpected end of file",
+                ))
+            }
+            n => {
+                buf = &mut buf[n..];
+                offset += n as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cached_read() {
+        let path = std::env::temp_dir().join(format!("hyperg-test-read-{}", std::process::id()));
+        std::fs::write(&path, b"0123456789").unwrap();
+
+        let mut buf = [0u8; 4];
+        read_exact_at(&path, 3, &mut buf).unwrap();
+        assert_eq!(&buf, b"3456");
+        assert!(cache().lock().unwrap().files.contains_key(&path));
+
+        assert!(read_exact_at(&path, 8, &mut buf).is_err());
+        assert!(!cache().lock().unwrap().files.contains_key(&path));
+
+        let bytes = run(move || {
+            let mut buf = vec![0u8; 2];
+            read_exact_at(&path, 0, &mut buf)?;
+            close(&path);
+            std::fs::remove_file(&path)?;
+            Ok(buf)
+        })
+        .wait()
+        .unwrap();
+        assert_eq!(bytes, b"01");
+    }
+}
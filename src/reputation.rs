@@ -0,0 +1,419 @@
+//! Peer reputation. Offenses are counted per node id and per address, enough of them ban the peer
+//! for a time that doubles with every ban. State is kept in `reputation.json` in the db dir.
+
+use crate::error::{Error, ProtocolError};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Points since the last ban that trigger a new one.
+const BAN_POINTS: u32 = 10;
+
+/// Length of the first ban in seconds.
+const BASE_BAN: u64 = 600;
+
+/// Longest ban in seconds.
+const MAX_BAN: u64 = 7 * 24 * 3600;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Offense {
+    /// Block, range or manifest not matching its hash.
+    CorruptData,
+    /// Missing or invalid handshake, failed authentication or malformed packet.
+    ProtocolViolation,
+    Timeout,
+}
+
+impl Offense {
+    /// Offense a peer committed if a transfer with it failed with `e`.
+    pub fn of(e: &Error) -> Option<Offense> {
+        match e {
+            Error::InvalidBlockHash(_)
+            | Error::InvalidManifest(_)
+            | Error::InvalidRangeProof(_) => Some(Offense::CorruptData),
+            Error::ProtocolError(ProtocolError::MissingHandshake)
+            | Error::ProtocolError(ProtocolError::InvalidHandshake)
+            | Error::ProtocolError(ProtocolError::AuthFailed) => Some(Offense::ProtocolViolation),
+            // Request timeouts are ours, the peer may just be busy or far away.
+            Error::ProtocolError(ProtocolError::HandshakeTimeout)
+            | Error::ProtocolError(ProtocolError::PeerTimeout) => Some(Offense::Timeout),
+            _ => None,
+        }
+    }
+
+    fn points(self) -> u32 {
+        match self {
+            Offense::CorruptData => BAN_POINTS,
+            Offense::ProtocolViolation => BAN_POINTS / 2,
+            Offense::Timeout => BAN_POINTS / 5,
+        }
+    }
+}
+
+/// Peer is tracked by node id and by address separately, either of them can be banned.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[serde(into = "String", try_from = "String")]
+pub enum PeerKey {
+    Node(u128),
+    Addr(IpAddr),
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PeerKey::Node(node_id) => write!(f, "{:032x}", node_id),
+            PeerKey::Addr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+impl From<PeerKey> for String {
+    fn from(key: PeerKey) -> Self {
+        key.to_string()
+    }
+}
+
+impl TryFrom<String> for PeerKey {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        if let Ok(addr) = s.parse() {
+            return Ok(PeerKey::Addr(addr));
+        }
+        match u128::from_str_radix(&s, 16) {
+            Ok(node_id) if s.len() == 32 => Ok(PeerKey::Node(node_id)),
+            _ => Err(format!(
+                "invalid peer: {} (expected node id or ip address)",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Default, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub struct Record {
+    pub corrupt_data: u32,
+    pub protocol_violations: u32,
+    pub timeouts: u32,
+    /// Offense points since the last ban.
+    pub points: u32,
+    pub bans: u32,
+    /// Unix time in seconds.
+    pub banned_until: Option<u64>,
+}
+
+impl Record {
+    fn is_banned(&self, now: u64) -> bool {
+        self.banned_until.is_some_and(|until| until > now)
+    }
+
+    fn offend(&mut self, offense: Offense, now: u64) {
+        match offense {
+            Offense::CorruptData => self.corrupt_data += 1,
+            Offense::ProtocolViolation => self.protocol_violations += 1,
+            Offense::Timeout => self.timeouts += 1,
+        }
+        self.points += offense.points();
+        if self.points >= BAN_POINTS {
+            let length = BASE_BAN.saturating_mul(1 << self.bans.min(16)).min(MAX_BAN);
+            self.points = 0;
+            self.bans += 1;
+            self.banned_until = Some(now + length);
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Ban {
+    pub peer: PeerKey,
+    #[serde(flatten)]
+    pub record: Record,
+}
+
+/// Writes state on its own thread, so reports don't wait for the disk. Only the newest
+/// snapshot is written, one write at a time.
+#[derive(Default)]
+struct Saver {
+    state: Mutex<SaverState>,
+}
+
+#[derive(Default)]
+struct SaverState {
+    /// Not written yet.
+    snapshot: Option<Vec<u8>>,
+    writer: Option<thread::JoinHandle<()>>,
+}
+
+impl Saver {
+    fn submit(self: &Arc<Self>, path: PathBuf, snapshot: Vec<u8>) {
+        let mut state = self.state.lock().unwrap();
+        state.snapshot = Some(snapshot);
+        if state.writer.is_none() {
+            let saver = self.clone();
+            state.writer = Some(thread::spawn(move || saver.write_pending(&path)));
+        }
+    }
+
+    fn write_pending(&self, path: &Path) {
+        loop {
+            let snapshot = {
+                let mut state = self.state.lock().unwrap();
+                match state.snapshot.take() {
+                    Some(snapshot) => snapshot,
+                    None => {
+                        state.writer = None;
+                        return;
+                    }
+                }
+            };
+            if let Err(e) = write_file(path, &snapshot) {
+                log::error!("failed to save peer reputation: {}", e);
+            }
+        }
+    }
+
+    /// Waits for snapshots submitted so far to be written.
+    #[cfg(test)]
+    fn flush(&self) {
+        let writer = self.state.lock().unwrap().writer.take();
+        if let Some(writer) = writer {
+            writer.join().unwrap();
+        }
+    }
+}
+
+/// Replaces `path` with `contents`, readers see either the old or the new file.
+fn write_file(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let temp = path.with_extension("json.tmp");
+    let mut file = fs::File::create(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+#[derive(Default)]
+struct Reputation {
+    path: Option<PathBuf>,
+    peers: HashMap<PeerKey, Record>,
+    saver: Arc<Saver>,
+}
+
+impl Reputation {
+    fn load(path: PathBuf) -> Result<Self, Error> {
+        let peers = if path.exists() {
+            let entries: Vec<(PeerKey, Record)> =
+                serde_json::from_reader(fs::OpenOptions::new().read(true).open(&path)?)?;
+            entries.into_iter().collect()
+        } else {
+            HashMap::new()
+        };
+        Ok(Reputation {
+            path: Some(path),
+            peers,
+            saver: Arc::default(),
+        })
+    }
+
+    fn save(&self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let entries: Vec<_> = self.peers.iter().collect();
+        match serde_json::to_vec_pretty(&entries) {
+            Ok(snapshot) => self.saver.submit(path.clone(), snapshot),
+            Err(e) => log::error!("failed to save peer reputation: {}", e),
+        }
+    }
+
+    fn report(&mut self, keys: &[PeerKey], offense: Offense, now: u64) {
+        for key in keys {
+            let record = self.peers.entry(*key).or_default();
+            let was_banned = record.is_banned(now);
+            record.offend(offense, now);
+            if !was_banned && record.is_banned(now) {
+                log::warn!(
+                    "peer {} banned for {}s after {:?}",
+                    key,
+                    record.banned_until.unwrap_or(now) - now,
+                    offense
+                );
+            }
+        }
+        self.save();
+    }
+
+    fn is_banned(&self, keys: &[PeerKey], now: u64) -> bool {
+        keys.iter().any(|key| {
+            self.peers
+                .get(key)
+                .is_some_and(|record| record.is_banned(now))
+        })
+    }
+
+    fn bans(&self, now: u64) -> Vec<Ban> {
+        self.peers
+            .iter()
+            .filter(|(_, record)| record.is_banned(now))
+            .map(|(peer, record)| Ban {
+                peer: *peer,
+                record: record.clone(),
+            })
+            .collect()
+    }
+
+    /// Forgets `peer`, or every peer. Returns whether anything was removed.
+    fn clear(&mut self, peer: Option<PeerKey>) -> bool {
+        let cleared = match peer {
+            Some(peer) => self.peers.remove(&peer).is_some(),
+            None => self.peers.drain().count() > 0,
+        };
+        if cleared {
+            self.save();
+        }
+        cleared
+    }
+}
+
+fn reputation() -> &'static Mutex<Reputation> {
+    static REPUTATION: OnceLock<Mutex<Reputation>> = OnceLock::new();
+    REPUTATION.get_or_init(Default::default)
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+fn keys(node_id: Option<u128>, addr: IpAddr) -> Vec<PeerKey> {
+    node_id
+        .map(PeerKey::Node)
+        .into_iter()
+        .chain(Some(PeerKey::Addr(addr)))
+        .collect()
+}
+
+/// Loads state kept in `dir`, offenses reported later are saved there.
+pub fn init(dir: &Path) {
+    match Reputation::load(dir.join("reputation.json")) {
+        Ok(loaded) => *reputation().lock().unwrap() = loaded,
+        Err(e) => log::error!("failed to load peer reputation: {}", e),
+    }
+}
+
+pub fn report(node_id: Option<u128>, addr: IpAddr, offense: Offense) {
+    reputation()
+        .lock()
+        .unwrap()
+        .report(&keys(node_id, addr), offense, unix_now())
+}
+
+/// Reports the offense behind `e`, if the peer is to blame for it.
+pub fn report_error(node_id: Option<u128>, addr: IpAddr, e: &Error) {
+    if let Some(offense) = Offense::of(e) {
+        report(node_id, addr, offense)
+    }
+}
+
+pub fn is_banned(node_id: Option<u128>, addr: IpAddr) -> bool {
+    reputation()
+        .lock()
+        .unwrap()
+        .is_banned(&keys(node_id, addr), unix_now())
+}
+
+pub fn bans() -> Vec<Ban> {
+    reputation().lock().unwrap().bans(unix_now())
+}
+
+pub fn clear(peer: Option<PeerKey>) -> bool {
+    reputation().lock().unwrap().clear(peer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_bans() {
+        let addr: IpAddr = "10.0.0.1".parse().unwrap();
+        let other: IpAddr = "10.0.0.2".parse().unwrap();
+        let peer = keys(Some(7), addr);
+        let mut reputation = Reputation::default();
+
+        reputation.report(&peer, Offense::ProtocolViolation, 0);
+        reputation.report(&peer, Offense::Timeout, 0);
+        reputation.report(&peer, Offense::Timeout, 0);
+        assert!(!reputation.is_banned(&peer, 0));
+        reputation.report(&peer, Offense::Timeout, 0);
+        assert!(reputation.is_banned(&peer, 0));
+        // Node is banned on any address.
+        assert!(reputation.is_banned(&keys(Some(7), other), 0));
+        assert!(!reputation.is_banned(&keys(Some(8), other), 0));
+        assert!(!reputation.is_banned(&peer, BASE_BAN));
+
+        // Next ban lasts twice as long.
+        reputation.report(&peer, Offense::CorruptData, BASE_BAN);
+        assert!(reputation.is_banned(&peer, 3 * BASE_BAN - 1));
+        assert!(!reputation.is_banned(&peer, 3 * BASE_BAN));
+        assert_eq!(reputation.bans(BASE_BAN).len(), 2);
+
+        assert!(reputation.clear(Some(PeerKey::Addr(addr))));
+        assert!(reputation.is_banned(&keys(Some(7), addr), BASE_BAN));
+        assert!(reputation.clear(None));
+        assert!(!reputation.is_banned(&peer, BASE_BAN));
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir =
+            std::env::temp_dir().join(format!("hyperg-test-reputation-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("reputation.json");
+        let peer = keys(Some(7), "10.0.0.1".parse().unwrap());
+
+        let mut reputation = Reputation::load(path.clone()).unwrap();
+        reputation.report(&peer, Offense::CorruptData, 0);
+        reputation.report(&peer, Offense::Timeout, 0);
+        reputation.saver.flush();
+        assert!(!dir.join("reputation.json.tmp").exists());
+        let loaded = Reputation::load(path).unwrap();
+        assert_eq!(loaded.peers, reputation.peers);
+        assert!(loaded.is_banned(&peer, 0));
+    }
+
+    #[test]
+    fn test_offense() {
+        assert_eq!(
+            Offense::of(&ProtocolError::PeerTimeout.into_err()),
+            Some(Offense::Timeout)
+        );
+        // Timed out waiting on our side.
+        assert_eq!(
+            Offense::of(&Error::Mailbox(actix::MailboxError::Timeout)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_peer_key() {
+        let node: PeerKey = serde_json::from_str("\"0000000000000000000000000000002a\"").unwrap();
+        assert_eq!(node, PeerKey::Node(42));
+        let addr: PeerKey = serde_json::from_str("\"::1\"").unwrap();
+        assert_eq!(addr.to_string(), "::1");
+        assert!(serde_json::from_str::<PeerKey>("\"2a\"").is_err());
+    }
+}
@@ -1,28 +1,50 @@
+use crate::admission;
+use crate::codec::{RejectReason, StCodec, StCommand};
 use crate::database::DatabaseManager;
+use crate::error::Error;
+use crate::reputation;
+use crate::transport::{self, TransportConfig};
 use actix::prelude::*;
 use actix_server::Io;
 use actix_service::service_fn;
+use bytes::BytesMut;
+use tokio_io::codec::Encoder;
 
+use std::sync::Arc;
 use std::{io, net};
 use tokio_tcp::TcpStream;
 
 pub fn new(
     db: Addr<DatabaseManager>,
     addr: impl net::ToSocketAddrs,
+    transport: Arc<TransportConfig>,
 ) -> io::Result<actix_server::Server> {
     Ok(actix_server::Server::build()
         .bind("gst", addr, move || {
             let db = db.clone();
+            let transport = transport.clone();
             service_fn(move |stream: Io<TcpStream>| {
                 let (tcp_stream, (), _) = stream.into_parts();
                 let peer_addr = tcp_stream.peer_addr()?;
+                if reputation::is_banned(None, peer_addr.ip()) {
+                    log::warn!("Connection from: {} rejected: banned", peer_addr);
+                    reject(tcp_stream, transport.clone(), RejectReason::Banned);
+                    return Ok(());
+                }
+                let ticket = match admission::admit(peer_addr.ip()) {
+                    Ok(ticket) => ticket,
+                    Err(reason) => {
+                        log::warn!("Connection from: {} rejected: {}", peer_addr, reason);
+                        reject(tcp_stream, transport.clone(), reason);
+                        return Ok(());
+                    }
+                };
                 log::info!("Connection from: {}", peer_addr);
-                let conn = crate::connection::Connection::new(
-                    db.clone(),
-                    tcp_stream,
-                    peer_addr,
-                    &crate::user_report::UserReportHandle::empty(),
-                );
+                let db = db.clone();
+                let conn =
+                    transport::accept(tcp_stream, transport.clone()).and_then(move |transport| {
+                        crate::connection::Connection::accept(db, transport, peer_addr, ticket)
+                    });
                 Arbiter::spawn(
                     conn.and_then(|_| Ok(()))
                         .map_err(|e| log::error!("failed to initalize connection: {}", e)),
@@ -32,3 +54,34 @@ pub fn new(
         })?
         .start())
 }
+
+/// Tells the peer why it was refused and closes the connection. When too many rejections are
+/// already in progress the connection is closed right away.
+fn reject(tcp_stream: TcpStream, transport: Arc<TransportConfig>, reason: RejectReason) {
+    let rejecting = match admission::start_reject() {
+        Some(rejecting) => rejecting,
+        None => return,
+    };
+    let mut buf = BytesMut::new();
+    if StCodec::default()
+        .encode(StCommand::Reject(reason), &mut buf)
+        .is_err()
+    {
+        return;
+    }
+    Arbiter::spawn(
+        transport::accept(tcp_stream, transport)
+            .and_then(move |transport| {
+                tokio_io::io::write_all(transport, buf)
+                    .and_then(|(transport, _)| tokio_io::io::shutdown(transport))
+                    .from_err()
+            })
+            .then(move |r: Result<_, Error>| {
+                drop(rejecting);
+                if let Err(e) = r {
+                    log::debug!("failed to send rejection: {}", e);
+                }
+                Ok(())
+            }),
+    );
+}
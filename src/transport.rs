@@ -0,0 +1,561 @@
+use crate::error::{Error, ProtocolError};
+use bytes::{BigEndian, ByteOrder, BytesMut};
+use futures::{future, prelude::*};
+use std::cmp::min;
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_io::io::{read_exact, write_all};
+use tokio_io::{try_nb, AsyncRead, AsyncWrite};
+use tokio_tcp::TcpStream;
+
+/// Noise protocol used for encrypted peer connections.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Sent by the initiator before the first handshake message. The first byte is not a valid
+/// opcode, so plaintext peers reject it and the server can tell both kinds of peers apart.
+const NOISE_PREAMBLE: [u8; 4] = [0xff, b'h', b'g', 1];
+
+const MAX_FRAME_SIZE: usize = 65535;
+
+const TAG_SIZE: usize = 16;
+
+const MAX_PAYLOAD_SIZE: usize = MAX_FRAME_SIZE - TAG_SIZE;
+
+const TRANSPORT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EncryptionMode {
+    /// Plaintext only.
+    Off,
+    /// Encrypt when the peer supports it, fall back to plaintext when it hangs up on the
+    /// encrypted handshake. A handshake the peer answered never falls back.
+    Preferred,
+    /// Refuse plaintext peers.
+    Required,
+}
+
+impl FromStr for EncryptionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(EncryptionMode::Off),
+            "preferred" => Ok(EncryptionMode::Preferred),
+            "required" => Ok(EncryptionMode::Required),
+            _ => Err(format!(
+                "invalid encryption mode: {} (expected off, preferred or required)",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for EncryptionMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            EncryptionMode::Off => "off",
+            EncryptionMode::Preferred => "preferred",
+            EncryptionMode::Required => "required",
+        })
+    }
+}
+
+/// Noise static key is generated per process. Peers prove it belongs to their node id by
+/// signing the handshake hash in `auth`.
+pub struct TransportConfig {
+    pub mode: EncryptionMode,
+    static_key: Vec<u8>,
+}
+
+impl TransportConfig {
+    pub fn new(mode: EncryptionMode) -> Result<Arc<Self>, Error> {
+        let keypair = snow::Builder::new(NOISE_PARAMS.parse()?).generate_keypair()?;
+        Ok(Arc::new(TransportConfig {
+            mode,
+            static_key: keypair.private,
+        }))
+    }
+
+    fn builder(&self) -> snow::Builder<'_> {
+        snow::Builder::new(NOISE_PARAMS.parse().unwrap())
+            .local_private_key(&self.static_key)
+            .prologue(&NOISE_PREAMBLE)
+    }
+}
+
+/// Connection to a peer, optionally wrapped in a Noise session.
+pub enum Transport {
+    Plain(TcpStream),
+    Noise(Box<NoiseStream>),
+}
+
+impl Transport {
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            Transport::Plain(_) => false,
+            Transport::Noise(_) => true,
+        }
+    }
+
+    /// Noise handshake hash, identical on both ends of the session. Empty for plaintext.
+    pub fn handshake_hash(&self) -> Vec<u8> {
+        match self {
+            Transport::Plain(_) => Vec::new(),
+            Transport::Noise(s) => s.handshake_hash.clone(),
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.read(buf),
+            Transport::Noise(s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(s) => s.write(buf),
+            Transport::Noise(s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Transport::Plain(s) => s.flush(),
+            Transport::Noise(s) => s.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Transport {}
+
+impl AsyncWrite for Transport {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match self {
+            Transport::Plain(s) => AsyncWrite::shutdown(s),
+            Transport::Noise(s) => s.shutdown(),
+        }
+    }
+}
+
+/// Encrypted stream. Every frame is a big endian `u16` length followed by a Noise transport
+/// message.
+pub struct NoiseStream {
+    io: TcpStream,
+    session: snow::TransportState,
+    handshake_hash: Vec<u8>,
+    read_buf: BytesMut,
+    plain: Vec<u8>,
+    plain_pos: usize,
+    write_buf: Vec<u8>,
+    write_pos: usize,
+}
+
+impl NoiseStream {
+    fn new(io: TcpStream, handshake: snow::HandshakeState) -> Result<Self, Error> {
+        if handshake.get_remote_static().is_none() {
+            return Err(ProtocolError::InvalidHandshake.into_err());
+        }
+        Ok(NoiseStream {
+            io,
+            handshake_hash: handshake.get_handshake_hash().to_vec(),
+            session: handshake.into_transport_mode()?,
+            read_buf: BytesMut::new(),
+            plain: Vec::new(),
+            plain_pos: 0,
+            write_buf: Vec::new(),
+            write_pos: 0,
+        })
+    }
+
+    fn next_frame(&mut self) -> io::Result<bool> {
+        if self.read_buf.len() < 2 {
+            return Ok(false);
+        }
+        let frame_size = BigEndian::read_u16(&self.read_buf[..2]) as usize;
+        if self.read_buf.len() < frame_size + 2 {
+            return Ok(false);
+        }
+        let frame = self.read_buf.split_to(frame_size + 2);
+        self.plain.resize(frame_size, 0);
+        let n = self
+            .session
+            .read_message(&frame[2..], &mut self.plain)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        self.plain.truncate(n);
+        self.plain_pos = 0;
+        Ok(true)
+    }
+
+    fn write_pending(&mut self) -> io::Result<()> {
+        while self.write_pos < self.write_buf.len() {
+            let n = self.io.write(&self.write_buf[self.write_pos..])?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            self.write_pos += n;
+        }
+        self.write_buf.clear();
+        self.write_pos = 0;
+        Ok(())
+    }
+}
+
+impl Read for NoiseStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if self.plain_pos < self.plain.len() {
+                let n = min(buf.len(), self.plain.len() - self.plain_pos);
+                buf[..n].copy_from_slice(&self.plain[self.plain_pos..self.plain_pos + n]);
+                self.plain_pos += n;
+                return Ok(n);
+            }
+            if self.next_frame()? {
+                continue;
+            }
+            let mut chunk = [0u8; 16 * 1024];
+            let n = self.io.read(&mut chunk)?;
+            if n == 0 {
+                return if self.read_buf.is_empty() {
+                    Ok(0)
+                } else {
+                    Err(io::ErrorKind::UnexpectedEof.into())
+                };
+            }
+            self.read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl Write for NoiseStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.write_pending()?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = min(buf.len(), MAX_PAYLOAD_SIZE);
+        self.write_buf.resize(2 + n + TAG_SIZE, 0);
+        let frame_size = self
+            .session
+            .write_message(&buf[..n], &mut self.write_buf[2..])
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        BigEndian::write_u16(&mut self.write_buf[..2], frame_size as u16);
+        self.write_buf.truncate(2 + frame_size);
+        // The frame is already accepted, the rest will be sent on the next write or flush.
+        match self.write_pending() {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            r => r?,
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_pending()?;
+        self.io.flush()
+    }
+}
+
+impl AsyncRead for NoiseStream {}
+
+impl AsyncWrite for NoiseStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        try_nb!(self.write_pending());
+        AsyncWrite::shutdown(&mut self.io)
+    }
+}
+
+fn write_frame(
+    io: TcpStream,
+    prefix: &[u8],
+    message: &[u8],
+) -> impl Future<Item = TcpStream, Error = Error> {
+    let mut buf = Vec::with_capacity(prefix.len() + 2 + message.len());
+    buf.extend_from_slice(prefix);
+    buf.extend_from_slice(&[0, 0]);
+    BigEndian::write_u16(&mut buf[prefix.len()..], message.len() as u16);
+    buf.extend_from_slice(message);
+    write_all(io, buf).map(|(io, _)| io).from_err()
+}
+
+fn read_frame(io: TcpStream) -> impl Future<Item = (TcpStream, Vec<u8>), Error = Error> {
+    read_exact(io, [0u8; 2])
+        .and_then(|(io, size)| read_exact(io, vec![0u8; BigEndian::read_u16(&size) as usize]))
+        .from_err()
+}
+
+fn with_timeout<F: Future<Error = Error>>(f: F) -> impl Future<Item = F::Item, Error = Error> {
+    tokio_timer::Timeout::new(f, TRANSPORT_HANDSHAKE_TIMEOUT).map_err(|e| {
+        if e.is_elapsed() {
+            ProtocolError::HandshakeTimeout.into_err()
+        } else {
+            e.into_inner()
+                .unwrap_or(Error::ServiceFail("transport timer"))
+        }
+    })
+}
+
+fn initiate(
+    io: TcpStream,
+    config: &TransportConfig,
+) -> impl Future<Item = Transport, Error = Error> {
+    let mut buf = vec![0u8; MAX_FRAME_SIZE];
+    let handshake = config
+        .builder()
+        .build_initiator()
+        .and_then(|mut handshake| {
+            let n = handshake.write_message(&[], &mut buf)?;
+            Ok((handshake, n))
+        });
+
+    future::result(handshake)
+        .from_err()
+        .and_then(move |(handshake, n)| {
+            write_frame(io, &NOISE_PREAMBLE, &buf[..n])
+                .and_then(|io| {
+                    // Peers without encryption drop the connection on the preamble.
+                    read_frame(io).map_err(|e| match e {
+                        Error::IO(ref e)
+                            if e.kind() == io::ErrorKind::UnexpectedEof
+                                || e.kind() == io::ErrorKind::ConnectionReset =>
+                        {
+                            ProtocolError::EncryptionDisabled.into_err()
+                        }
+                        e => e,
+                    })
+                })
+                .and_then(move |(io, message)| {
+                    let mut handshake = handshake;
+                    let n = handshake
+                        .read_message(&message, &mut buf)
+                        .and_then(|_| handshake.write_message(&[], &mut buf))?;
+                    Ok((io, handshake, buf, n))
+                })
+        })
+        .and_then(move |(io, handshake, buf, n)| {
+            write_frame(io, &[], &buf[..n]).and_then(move |io| {
+                Ok(Transport::Noise(Box::new(NoiseStream::new(io, handshake)?)))
+            })
+        })
+}
+
+fn respond(
+    io: TcpStream,
+    config: &TransportConfig,
+) -> impl Future<Item = Transport, Error = Error> {
+    let mut buf = vec![0u8; MAX_FRAME_SIZE];
+    let handshake = config.builder().build_responder();
+
+    future::result(handshake)
+        .from_err()
+        .and_then(move |handshake| {
+            read_exact(io, [0u8; 4])
+                .from_err()
+                .and_then(|(io, preamble)| {
+                    if preamble == NOISE_PREAMBLE {
+                        Ok(io)
+                    } else {
+                        Err(ProtocolError::InvalidHandshake.into_err())
+                    }
+                })
+                .and_then(read_frame)
+                .and_then(move |(io, message)| {
+                    let mut handshake = handshake;
+                    let n = handshake
+                        .read_message(&message, &mut buf)
+                        .and_then(|_| handshake.write_message(&[], &mut buf))?;
+                    Ok((io, handshake, buf, n))
+                })
+                .and_then(|(io, handshake, buf, n)| {
+                    write_frame(io, &[], &buf[..n])
+                        .and_then(read_frame)
+                        .and_then(move |(io, message)| {
+                            let mut handshake = handshake;
+                            let mut buf = buf;
+                            handshake.read_message(&message, &mut buf)?;
+                            Ok(Transport::Noise(Box::new(NoiseStream::new(io, handshake)?)))
+                        })
+                })
+        })
+}
+
+/// Opens connection to `addr` honoring configured encryption mode.
+pub fn connect(
+    addr: net::SocketAddr,
+    config: Arc<TransportConfig>,
+) -> impl Future<Item = Transport, Error = Error> {
+    TcpStream::connect(&addr)
+        .from_err()
+        .and_then(move |io| match config.mode {
+            EncryptionMode::Off => future::Either::A(future::ok(Transport::Plain(io))),
+            EncryptionMode::Required => {
+                future::Either::B(future::Either::A(with_timeout(initiate(io, &config))))
+            }
+            EncryptionMode::Preferred => future::Either::B(future::Either::B(
+                with_timeout(initiate(io, &config)).or_else(move |e| match e {
+                    Error::ProtocolError(ProtocolError::EncryptionDisabled) => {
+                        log::warn!("{} does not support encryption, retrying plaintext", addr);
+                        future::Either::A(
+                            TcpStream::connect(&addr).from_err().map(Transport::Plain),
+                        )
+                    }
+                    e => future::Either::B(future::err(e)),
+                }),
+            )),
+        })
+}
+
+struct Sniff(Option<TcpStream>);
+
+impl Future for Sniff {
+    type Item = (TcpStream, Option<u8>);
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut first = [0u8; 1];
+        let n = futures::try_ready!(self.0.as_mut().unwrap().poll_peek(&mut first));
+        let io = self.0.take().unwrap();
+        Ok(Async::Ready((
+            io,
+            if n == 0 { None } else { Some(first[0]) },
+        )))
+    }
+}
+
+/// Accepts incoming connection. Encrypted and plaintext peers are told apart by the first byte
+/// they send.
+pub fn accept(
+    io: TcpStream,
+    config: Arc<TransportConfig>,
+) -> impl Future<Item = Transport, Error = Error> {
+    with_timeout(Sniff(Some(io)).and_then(move |(io, first)| {
+        let encrypted = first == Some(NOISE_PREAMBLE[0]);
+        match (config.mode, encrypted) {
+            (EncryptionMode::Off, true) => {
+                future::Either::A(future::err(ProtocolError::EncryptionDisabled.into_err()))
+            }
+            (EncryptionMode::Required, false) => {
+                future::Either::A(future::err(ProtocolError::EncryptionRequired.into_err()))
+            }
+            (_, false) => future::Either::A(future::ok(Transport::Plain(io))),
+            (_, true) => future::Either::B(respond(io, &config)),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio_tcp::TcpListener;
+
+    /// Connects over loopback and sends `size` bytes from the client to the server. Returns
+    /// whether the server and client side ended up encrypted.
+    fn exchange(
+        server_mode: EncryptionMode,
+        client_mode: EncryptionMode,
+        accepts: u64,
+        size: usize,
+    ) -> Result<(bool, bool), Error> {
+        let server_config = TransportConfig::new(server_mode).unwrap();
+        let client_config = TransportConfig::new(client_mode).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let data: Vec<u8> = (0..size).map(|i| (i % 251) as u8).collect();
+        let expected = data.clone();
+
+        let server = listener
+            .incoming()
+            .take(accepts)
+            .from_err()
+            .and_then(move |io| accept(io, server_config.clone()).then(Ok))
+            .collect()
+            .and_then(|mut results: Vec<Result<Transport, Error>>| results.pop().unwrap())
+            .and_then(move |io| {
+                let session = (io.is_encrypted(), io.handshake_hash());
+                read_exact(io, vec![0u8; size])
+                    .from_err()
+                    .map(move |(_, received)| {
+                        assert!(received == expected);
+                        session
+                    })
+            });
+        let client = connect(addr, client_config).and_then(|io| {
+            let session = (io.is_encrypted(), io.handshake_hash());
+            write_all(io, data).from_err().map(move |_| session)
+        });
+
+        actix::System::new("test")
+            .block_on(server.join(client))
+            .map(|((server, server_hash), (client, client_hash))| {
+                // Both ends sign the same session in auth.
+                assert_eq!(server_hash, client_hash);
+                assert_eq!(server_hash.is_empty(), !server);
+                (server, client)
+            })
+    }
+
+    #[test]
+    fn test_encrypted_peer() {
+        let r = exchange(
+            EncryptionMode::Preferred,
+            EncryptionMode::Required,
+            1,
+            3 * MAX_FRAME_SIZE + 17,
+        );
+        assert_eq!(r.unwrap(), (true, true));
+    }
+
+    #[test]
+    fn test_plaintext_peer() {
+        let r = exchange(EncryptionMode::Preferred, EncryptionMode::Off, 1, 1024);
+        assert_eq!(r.unwrap(), (false, false));
+    }
+
+    #[test]
+    fn test_preferred_fallback() {
+        let r = exchange(EncryptionMode::Off, EncryptionMode::Preferred, 2, 1024);
+        assert_eq!(r.unwrap(), (false, false));
+    }
+
+    #[test]
+    fn test_preferred_fails_closed() {
+        let config = TransportConfig::new(EncryptionMode::Preferred).unwrap();
+        let listener = TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // Answers the handshake with garbage, then would accept a plaintext retry.
+        let server = listener
+            .incoming()
+            .take(1)
+            .from_err()
+            .and_then(|io| {
+                read_exact(io, [0u8; 4])
+                    .from_err()
+                    .and_then(|(io, _)| read_frame(io))
+                    .and_then(|(io, _)| write_frame(io, &[], &[7; 48]))
+            })
+            .collect()
+            .map(|_| ());
+        let client = connect(addr, config).then(Ok::<_, Error>);
+
+        let (_, client) = actix::System::new("test")
+            .block_on(server.join(client))
+            .unwrap();
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn test_required_rejects_plaintext() {
+        assert!(exchange(EncryptionMode::Required, EncryptionMode::Off, 1, 1024).is_err());
+    }
+}